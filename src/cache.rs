@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::embedding::{Embedder, embed_in_batches};
+
+/// Name of the cache file inside the cache directory.
+const CACHE_FILE_NAME: &str = "embedding_cache.bin";
+
+/// Task type recorded in the keys of document embeddings.
+const DOCUMENT_TASK: &str = "document";
+
+type Key = [u8; 32];
+
+/// The cache key of `text` embedded by `model` for `task`.
+fn cache_key(model: &str, task: &str, text: &str) -> Key {
+    let mut hasher = Sha256::new();
+    for part in [model, task, text] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize().into()
+}
+
+/// Where a cached vector is kept.
+enum Vector {
+    /// In the cache file, as `dimension` values starting at byte `offset`.
+    Saved { offset: u64, dimension: u32 },
+    /// In memory, until the next `flush` appends it to the file.
+    Unsaved(Vec<f32>),
+}
+
+/// A cached vector and the model that produced it.
+struct Entry {
+    model: Arc<str>,
+    vector: Vector,
+}
+
+/// The cache entries by key, and the keys added since the last `flush`.
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<Key, Entry>,
+    unsaved: Vec<Key>,
+}
+
+/// Vectors of previously embedded texts, keyed by a hash of the embedding model,
+/// task type and text, so a chunk is never sent to the embedding API twice even
+/// when its file is moved or renamed.
+///
+/// Entries are appended to a binary file as they are added: a 32-byte key, a
+/// little-endian `u16` model name length and the name, a `u32` dimension and the
+/// vector as `f32`s. A torn entry at the end of the file is ignored on load.
+/// Only the keys and the offsets of their vectors are kept in memory; vectors are
+/// read from the file when they are used.
+pub struct EmbeddingCache {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+}
+
+/// Size and contents of an embedding cache.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub path: PathBuf,
+    pub entries: usize,
+    /// Size of the cache file in bytes.
+    pub bytes: u64,
+    /// Number of entries for each embedding model.
+    pub models: BTreeMap<String, usize>,
+}
+
+impl EmbeddingCache {
+    /// Open the cache in `dir`, which is created when the cache is first flushed.
+    pub fn open(dir: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let path = dir.join(CACHE_FILE_NAME);
+        let entries = match File::open(&path) {
+            Ok(file) => {
+                let len = file.metadata()?.len();
+                let (entries, valid_len) = read_index(file, len)?;
+                // Cut off a torn entry so that later appends line up
+                if valid_len < len {
+                    OpenOptions::new().write(true).open(&path)?.set_len(valid_len)?;
+                }
+                entries
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(EmbeddingCache { path, entries: Mutex::new(Entries { entries, unsaved: Vec::new() }) })
+    }
+
+    /// Embed document chunks with `embedder`, taking vectors from the cache where
+    /// possible. Returns the vectors and how many texts had to be sent to the embedder.
+    pub async fn embed_documents(
+        &self,
+        embedder: &dyn Embedder,
+        texts: &[String],
+    ) -> Result<(Vec<Vec<f32>>, usize), Box<dyn std::error::Error + Send + Sync>> {
+        let model: Arc<str> = embedder.model().into();
+        let keys: Vec<Key> = texts.iter().map(|t| cache_key(&model, DOCUMENT_TASK, t)).collect();
+        let mut vectors: Vec<Option<Vec<f32>>> = vec![None; texts.len()];
+        let mut saved = Vec::new();
+        {
+            let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            for (i, key) in keys.iter().enumerate() {
+                match entries.entries.get(key).map(|e| &e.vector) {
+                    Some(&Vector::Saved { offset, dimension }) => saved.push((i, offset, dimension)),
+                    Some(Vector::Unsaved(vector)) => vectors[i] = Some(vector.clone()),
+                    None => {}
+                }
+            }
+        }
+        if !saved.is_empty() {
+            // Read in file order so the reads move forward through the file
+            saved.sort_by_key(|&(_, offset, _)| offset);
+            let mut file = BufReader::new(File::open(&self.path)?);
+            for (i, offset, dimension) in saved {
+                file.seek(SeekFrom::Start(offset))?;
+                vectors[i] = Some(read_vector(&mut file, dimension)?);
+            }
+        }
+
+        let missing: Vec<usize> = (0..texts.len()).filter(|&i| vectors[i].is_none()).collect();
+        let missing_texts: Vec<String> = missing.iter().map(|&i| texts[i].clone()).collect();
+        let embeddings = embed_in_batches(embedder, &missing_texts).await?;
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let Entries { entries, unsaved } = &mut *entries;
+        for (&i, vector) in missing.iter().zip(embeddings) {
+            let entry = Entry { model: model.clone(), vector: Vector::Unsaved(vector.clone()) };
+            if entries.insert(keys[i], entry).is_none() {
+                unsaved.push(keys[i]);
+            }
+            vectors[i] = Some(vector);
+        }
+        Ok((vectors.into_iter().flatten().collect(), missing.len()))
+    }
+
+    /// Append the entries added since the last flush to the cache file, after
+    /// which their vectors are read back from the file rather than kept in memory.
+    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let Entries { entries, unsaved } = &mut *entries;
+        if unsaved.is_empty() {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut offset = file.metadata()?.len();
+        let mut writer = BufWriter::new(file);
+        let mut written = Vec::new();
+        for key in unsaved.iter() {
+            if let Some(Entry { model, vector: Vector::Unsaved(vector) }) = entries.get(key) {
+                let vector_offset = write_entry(&mut writer, key, model, vector, &mut offset)?;
+                written.push((*key, vector_offset, vector.len() as u32));
+            }
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        for (key, offset, dimension) in written {
+            if let Some(entry) = entries.get_mut(&key) {
+                entry.vector = Vector::Saved { offset, dimension };
+            }
+        }
+        unsaved.clear();
+        Ok(())
+    }
+
+    /// Number of entries and their models, along with the size of the cache file.
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut models = BTreeMap::new();
+        for entry in entries.entries.values() {
+            *models.entry(entry.model.to_string()).or_insert(0) += 1;
+        }
+        CacheStats {
+            path: self.path.clone(),
+            entries: entries.entries.len(),
+            bytes: fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0),
+            models,
+        }
+    }
+
+    /// Drop every entry that is not the vector of one of `texts` under its model,
+    /// given as `(model, text)` pairs, and rewrite the cache file. Returns how many
+    /// entries were removed.
+    pub fn retain_documents<'a>(
+        &self,
+        texts: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        self.flush()?;
+        let keep: HashSet<Key> = texts
+            .into_iter()
+            .map(|(model, text)| cache_key(model, DOCUMENT_TASK, text))
+            .collect();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let before = entries.entries.len();
+        entries.entries.retain(|key, _| keep.contains(key));
+        if entries.entries.len() == before {
+            return Ok(0);
+        }
+
+        // Copy the kept entries one at a time, in file order
+        let mut kept: Vec<(&Key, &Entry)> = entries.entries.iter().collect();
+        kept.sort_by_key(|(_, entry)| match entry.vector {
+            Vector::Saved { offset, .. } => offset,
+            Vector::Unsaved(_) => u64::MAX,
+        });
+        let tmp_path = self.path.with_extension("bin.tmp");
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut offset = 0;
+        let mut moved = Vec::with_capacity(kept.len());
+        for (key, entry) in kept {
+            let vector = match &entry.vector {
+                &Vector::Saved { offset, dimension } => {
+                    reader.seek(SeekFrom::Start(offset))?;
+                    read_vector(&mut reader, dimension)?
+                }
+                Vector::Unsaved(vector) => vector.clone(),
+            };
+            let vector_offset = write_entry(&mut writer, key, &entry.model, &vector, &mut offset)?;
+            moved.push((*key, vector_offset, vector.len() as u32));
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        for (key, offset, dimension) in moved {
+            if let Some(entry) = entries.entries.get_mut(&key) {
+                entry.vector = Vector::Saved { offset, dimension };
+            }
+        }
+        Ok(before - entries.entries.len())
+    }
+}
+
+/// Write one entry at byte `offset` of the cache file, advancing `offset` past it.
+/// Returns the offset of the vector.
+fn write_entry(writer: &mut impl Write, key: &Key, model: &str, vector: &[f32], offset: &mut u64) -> io::Result<u64> {
+    writer.write_all(key)?;
+    writer.write_all(&(model.len() as u16).to_le_bytes())?;
+    writer.write_all(model.as_bytes())?;
+    writer.write_all(&(vector.len() as u32).to_le_bytes())?;
+    for x in vector {
+        writer.write_all(&x.to_le_bytes())?;
+    }
+    let vector_offset = *offset + (32 + 2 + model.len() + 4) as u64;
+    *offset = vector_offset + vector.len() as u64 * 4;
+    Ok(vector_offset)
+}
+
+/// Read a vector of `dimension` values at the reader's position.
+fn read_vector(reader: &mut impl Read, dimension: u32) -> io::Result<Vec<f32>> {
+    let mut bytes = vec![0; dimension as usize * 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+}
+
+/// Read the keys, models and vector offsets of a cache file `len` bytes long,
+/// skipping over the vectors and stopping at the first incomplete entry. Returns
+/// them with the length of the file up to that point.
+fn read_index(file: File, len: u64) -> io::Result<(HashMap<Key, Entry>, u64)> {
+    let mut reader = BufReader::new(file);
+    // Each model name is kept once, however many entries it has
+    let mut models: HashMap<Vec<u8>, Arc<str>> = HashMap::new();
+    let mut entries = HashMap::new();
+    let mut valid = 0;
+    loop {
+        let mut key: Key = [0; 32];
+        let mut model_len = [0; 2];
+        let mut dimension = [0; 4];
+        let header = reader
+            .read_exact(&mut key)
+            .and_then(|_| reader.read_exact(&mut model_len))
+            .and_then(|_| {
+                let mut model = vec![0; u16::from_le_bytes(model_len) as usize];
+                reader.read_exact(&mut model)?;
+                reader.read_exact(&mut dimension)?;
+                Ok(model)
+            });
+        let model = match header {
+            Ok(model) => model,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok((entries, valid)),
+            Err(e) => return Err(e),
+        };
+        let dimension = u32::from_le_bytes(dimension);
+        let offset = valid + (32 + 2 + model.len() + 4) as u64;
+        let end = offset + dimension as u64 * 4;
+        if end > len {
+            return Ok((entries, valid));
+        }
+        reader.seek_relative(dimension as i64 * 4)?;
+        let model = models
+            .entry(model)
+            .or_insert_with_key(|name| String::from_utf8_lossy(name).into())
+            .clone();
+        entries.insert(key, Entry { model, vector: Vector::Saved { offset, dimension } });
+        valid = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embedding::HashEmbedder;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fisher-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn vectors_are_read_back_after_reopening() {
+        let dir = temp_dir("reopen");
+        let embedder = HashEmbedder::new(8);
+        let cache = EmbeddingCache::open(&dir).unwrap();
+        let (first, sent) = cache.embed_documents(&embedder, &texts(&["a", "b"])).await.unwrap();
+        assert_eq!(sent, 2);
+        cache.flush().unwrap();
+        let (again, sent) = cache.embed_documents(&embedder, &texts(&["b", "a", "c"])).await.unwrap();
+        assert_eq!(sent, 1);
+        assert_eq!(again[..2], [first[1].clone(), first[0].clone()]);
+        cache.flush().unwrap();
+
+        let cache = EmbeddingCache::open(&dir).unwrap();
+        let (reopened, sent) = cache.embed_documents(&embedder, &texts(&["c", "a", "b"])).await.unwrap();
+        assert_eq!(sent, 0);
+        assert_eq!(reopened, [again[2].clone(), first[0].clone(), first[1].clone()]);
+        assert_eq!(cache.stats().models.get("hash-8"), Some(&3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn torn_entries_are_cut_off() {
+        let dir = temp_dir("torn");
+        let embedder = HashEmbedder::new(8);
+        let cache = EmbeddingCache::open(&dir).unwrap();
+        cache.embed_documents(&embedder, &texts(&["a", "b"])).await.unwrap();
+        cache.flush().unwrap();
+        let path = dir.join(CACHE_FILE_NAME);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let cache = EmbeddingCache::open(&dir).unwrap();
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.stats().bytes, len / 2);
+        let (_, sent) = cache.embed_documents(&embedder, &texts(&["a", "b"])).await.unwrap();
+        assert_eq!(sent, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retained_vectors_survive_a_rewrite() {
+        let dir = temp_dir("retain");
+        let embedder = HashEmbedder::new(8);
+        let cache = EmbeddingCache::open(&dir).unwrap();
+        let (vectors, _) = cache.embed_documents(&embedder, &texts(&["a", "b", "c"])).await.unwrap();
+        let model = embedder.model();
+        assert_eq!(cache.retain_documents([(model.as_str(), "c"), (model.as_str(), "a")]).unwrap(), 1);
+
+        let (kept, sent) = cache.embed_documents(&embedder, &texts(&["a", "c"])).await.unwrap();
+        assert_eq!(sent, 0);
+        assert_eq!(kept, [vectors[0].clone(), vectors[2].clone()]);
+        let reopened = EmbeddingCache::open(&dir).unwrap();
+        assert_eq!(reopened.stats().entries, 2);
+        let (_, sent) = reopened.embed_documents(&embedder, &texts(&["a", "b", "c"])).await.unwrap();
+        assert_eq!(sent, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
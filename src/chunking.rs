@@ -0,0 +1,945 @@
+use std::path::Path;
+use std::str::FromStr;
+use sha2::{Digest, Sha256};
+use crate::config::ChunkConfig;
+use crate::files::Chunk;
+use crate::loaders::DocumentLoader;
+
+/// Version of the chunkers and loaders, part of every chunking fingerprint. Bump it
+/// when a change to either would split or label already indexed files differently.
+pub const CHUNKING_VERSION: u32 = 1;
+
+/// Separators tried in order by the recursive splitter: paragraphs, lines, sentences, words.
+const RECURSIVE_SEPARATORS: [&str; 4] = ["\n\n", "\n", ". ", " "];
+
+/// Splits the text of a document into chunks for embedding.
+pub trait Chunker: Send + Sync {
+    /// Split `text` into chunks, in order. Chunks are never empty or whitespace-only,
+    /// and their byte ranges point back into `text`.
+    fn chunk(&self, text: &str) -> Vec<Chunk>;
+}
+
+/// Unit that chunk sizes and overlaps are measured in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeUnit {
+    Characters,
+    /// Approximate model tokens, see `count_tokens`.
+    Tokens,
+}
+
+impl SizeUnit {
+    /// Size of `text` in this unit.
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            SizeUnit::Characters => text.chars().count(),
+            SizeUnit::Tokens => count_tokens(text),
+        }
+    }
+}
+
+impl FromStr for SizeUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chars" | "characters" => Ok(SizeUnit::Characters),
+            "tokens" => Ok(SizeUnit::Tokens),
+            other => Err(format!("Unknown chunk size unit: {}", other)),
+        }
+    }
+}
+
+/// How large chunks may be and how much consecutive chunks share.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkOptions {
+    pub unit: SizeUnit,
+    /// Largest chunk, unless a single indivisible piece is larger.
+    pub size: usize,
+    /// Amount of text repeated from the end of one chunk at the start of the next.
+    pub overlap: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            unit: SizeUnit::Characters,
+            size: 1000,
+            overlap: 100,
+        }
+    }
+}
+
+/// Lowercase extension of `path` and the strategy configured for it, falling back
+/// to the default strategy.
+fn strategy_for<'a>(path: &Path, config: &'a ChunkConfig) -> (String, &'a str) {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let strategy = config
+        .strategies
+        .get(&extension)
+        .unwrap_or(&config.default_strategy);
+    (extension, strategy)
+}
+
+/// Pick the chunker for `path` from the strategy configured for its extension,
+/// falling back to the default strategy.
+pub fn chunker_for(path: &Path, config: &ChunkConfig) -> Box<dyn Chunker> {
+    let (extension, strategy) = strategy_for(path, config);
+    match (strategy, Language::from_extension(&extension)) {
+        ("sentence", _) => Box::new(SentenceChunker { options: config.options }),
+        ("markdown", _) => Box::new(MarkdownChunker { options: config.options }),
+        ("records", _) => Box::new(RecordChunker { options: config.options }),
+        ("code", Some(language)) => Box::new(CodeChunker { options: config.options, language }),
+        _ => Box::new(RecursiveChunker { options: config.options }),
+    }
+}
+
+/// Fingerprint of everything that decides how `path` is split into chunks: the
+/// strategy and options configured for it, the settings of `loader`, which reads
+/// it, and `CHUNKING_VERSION`. Files chunked under another fingerprint are re-chunked.
+pub fn chunking_fingerprint(path: &Path, loader: &dyn DocumentLoader, config: &ChunkConfig) -> String {
+    let (extension, strategy) = strategy_for(path, config);
+    let description = format!(
+        "{} {} {} {:?} {}",
+        CHUNKING_VERSION,
+        extension,
+        strategy,
+        config.options,
+        loader.settings()
+    );
+    format!("{:x}", Sha256::digest(description))[..16].to_string()
+}
+
+/// Splits on the coarsest boundary that brings pieces under the size limit,
+/// trying paragraphs, then lines, sentences and words, and packs the pieces back
+/// together into chunks.
+pub struct RecursiveChunker {
+    pub options: ChunkOptions,
+}
+
+impl Chunker for RecursiveChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let mut pieces = Vec::new();
+        split_recursive(text, 0, text.len(), &RECURSIVE_SEPARATORS, &self.options, &mut pieces);
+        merge_pieces(text, &pieces, &self.options)
+    }
+}
+
+/// Packs whole sentences into chunks, treating blank lines as sentence ends.
+/// Sentences longer than a chunk are split on words.
+pub struct SentenceChunker {
+    pub options: ChunkOptions,
+}
+
+impl Chunker for SentenceChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let mut pieces = Vec::new();
+        for (start, end) in sentence_spans(text) {
+            split_recursive(text, start, end, &[" "], &self.options, &mut pieces);
+        }
+        merge_pieces(text, &pieces, &self.options)
+    }
+}
+
+/// Keeps each blank-line-separated group of records, as laid out by the CSV and
+/// JSON loaders, in a chunk of its own, splitting groups too large for one chunk
+/// between records.
+pub struct RecordChunker {
+    pub options: ChunkOptions,
+}
+
+impl Chunker for RecordChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for group in text.split_inclusive("\n\n") {
+            let mut pieces = Vec::new();
+            split_recursive(text, start, start + group.len(), &RECURSIVE_SEPARATORS[1..], &self.options, &mut pieces);
+            chunks.extend(merge_pieces(text, &pieces, &self.options));
+            start += group.len();
+        }
+        chunks
+    }
+}
+
+/// Splits Markdown into the sections under each heading, keeping fenced code
+/// blocks and tables whole, and labels every chunk with its heading breadcrumb
+/// such as `Guide > Install > Linux`. Chunks never span two sections.
+pub struct MarkdownChunker {
+    pub options: ChunkOptions,
+}
+
+/// A structural piece of a Markdown document.
+enum MarkdownBlock {
+    Heading { level: usize, title: String },
+    /// A code block or table, which is never split.
+    Atomic(usize, usize),
+    Paragraph(usize, usize),
+}
+
+impl Chunker for MarkdownChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut headings: Vec<(usize, String)> = Vec::new();
+        let mut pieces = Vec::new();
+
+        for block in markdown_blocks(text) {
+            match block {
+                MarkdownBlock::Heading { level, title } => {
+                    flush_section(text, &mut pieces, &headings, &self.options, &mut chunks);
+                    while headings.last().is_some_and(|(l, _)| *l >= level) {
+                        headings.pop();
+                    }
+                    headings.push((level, title));
+                }
+                MarkdownBlock::Atomic(start, end) => pieces.push((start, end)),
+                MarkdownBlock::Paragraph(start, end) => {
+                    split_recursive(text, start, end, &RECURSIVE_SEPARATORS[1..], &self.options, &mut pieces);
+                }
+            }
+        }
+        flush_section(text, &mut pieces, &headings, &self.options, &mut chunks);
+        chunks
+    }
+}
+
+/// Chunk the pieces of one section, labelling the chunks with the current headings.
+fn flush_section(
+    text: &str,
+    pieces: &mut Vec<(usize, usize)>,
+    headings: &[(usize, String)],
+    options: &ChunkOptions,
+    chunks: &mut Vec<Chunk>,
+) {
+    let breadcrumb = headings
+        .iter()
+        .map(|(_, title)| title.as_str())
+        .collect::<Vec<_>>()
+        .join(" > ");
+    for mut chunk in merge_pieces(text, pieces, options) {
+        if !breadcrumb.is_empty() {
+            chunk.heading = Some(breadcrumb.clone());
+        }
+        chunks.push(chunk);
+    }
+    pieces.clear();
+}
+
+/// Break Markdown into headings, code blocks, tables and paragraphs, with byte ranges.
+fn markdown_blocks(text: &str) -> Vec<MarkdownBlock> {
+    let mut blocks = Vec::new();
+    // Start of the open code fence and its marker
+    let mut fence: Option<(usize, &str)> = None;
+    // Open paragraph or table, and whether it is a table
+    let mut open: Option<(usize, usize, bool)> = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        let end = offset + line.trim_end().len();
+        offset += line.len();
+        let trimmed = line.trim();
+
+        if let Some((fence_start, marker)) = fence {
+            if trimmed.starts_with(marker) {
+                blocks.push(MarkdownBlock::Atomic(fence_start, end));
+                fence = None;
+            }
+            continue;
+        }
+
+        let is_table = trimmed.starts_with('|');
+        if let Some((open_start, open_end, open_table)) = open {
+            if trimmed.is_empty() || open_table != is_table || markdown_heading(trimmed).is_some() || is_fence(trimmed) {
+                blocks.push(if open_table {
+                    MarkdownBlock::Atomic(open_start, open_end)
+                } else {
+                    MarkdownBlock::Paragraph(open_start, open_end)
+                });
+                open = None;
+            } else {
+                open = Some((open_start, end, open_table));
+                continue;
+            }
+        }
+
+        if let Some(marker) = is_fence(trimmed).then(|| &trimmed[..3]) {
+            fence = Some((start + (line.len() - line.trim_start().len()), marker));
+        } else if let Some((level, title)) = markdown_heading(trimmed) {
+            blocks.push(MarkdownBlock::Heading { level, title });
+        } else if !trimmed.is_empty() {
+            open = Some((start, end, is_table));
+        }
+    }
+
+    match (fence, open) {
+        // An unclosed fence runs to the end of the document
+        (Some((fence_start, _)), _) => blocks.push(MarkdownBlock::Atomic(fence_start, text.len())),
+        (None, Some((start, end, true))) => blocks.push(MarkdownBlock::Atomic(start, end)),
+        (None, Some((start, end, false))) => blocks.push(MarkdownBlock::Paragraph(start, end)),
+        (None, None) => {}
+    }
+    blocks
+}
+
+/// Whether a trimmed line opens or closes a fenced code block.
+fn is_fence(line: &str) -> bool {
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+/// Parse an ATX heading such as `## Install ##` into its level and title.
+fn markdown_heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') && !rest.starts_with('\t') {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim();
+    Some((level, title.to_string()))
+}
+
+/// Programming languages understood by `CodeChunker`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    Rust,
+    Python,
+    /// TypeScript and JavaScript.
+    TypeScript,
+    Go,
+}
+
+impl Language {
+    /// Language of files with the given lowercase extension.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "rs" => Some(Language::Rust),
+            "py" | "pyi" => Some(Language::Python),
+            "ts" | "tsx" | "mts" | "cts" | "js" | "jsx" | "mjs" | "cjs" => Some(Language::TypeScript),
+            "go" => Some(Language::Go),
+            _ => None,
+        }
+    }
+
+    /// Separator between a type and its members in symbol names.
+    fn member_separator(self) -> &'static str {
+        match self {
+            Language::Rust => "::",
+            _ => ".",
+        }
+    }
+
+    /// Whether a trimmed line is a comment, attribute or decorator belonging to the item below it.
+    fn is_preface(self, line: &str) -> bool {
+        match self {
+            Language::Rust => line.starts_with("//") || line.starts_with("/*") || line.starts_with('*') || line.starts_with("#["),
+            Language::Python => line.starts_with('#') || line.starts_with('@'),
+            Language::TypeScript => line.starts_with("//") || line.starts_with("/*") || line.starts_with('*') || line.starts_with('@'),
+            Language::Go => line.starts_with("//") || line.starts_with("/*") || line.starts_with('*'),
+        }
+    }
+}
+
+/// Splits source code on top-level items such as functions, impls and classes, and
+/// records each chunk's symbol name. Items too large for one chunk are split on
+/// their methods, then on blank lines and lines.
+pub struct CodeChunker {
+    pub options: ChunkOptions,
+    pub language: Language,
+}
+
+/// Where a top-level item or member begins.
+struct ItemStart {
+    /// Line the item starts on, including the comments and attributes above it.
+    line: usize,
+    /// Symbol name, or `None` for imports and other code without one.
+    symbol: Option<String>,
+    /// Type whose members can be split out when the item is too large.
+    owner: Option<String>,
+}
+
+impl Chunker for CodeChunker {
+    fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let mut line_starts: Vec<usize> = text.split_inclusive('\n').map(|l| l.len()).scan(0, |offset, len| {
+            let start = *offset;
+            *offset += len;
+            Some(start)
+        }).collect();
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        line_starts.push(text.len());
+
+        let mut chunks = Vec::new();
+        let items = self.item_starts(&lines, 0, lines.len(), 0, None);
+        for (i, item) in items.iter().enumerate() {
+            let end = items.get(i + 1).map_or(lines.len(), |next| next.line);
+            self.chunk_item(text, &lines, &line_starts, item, end, &mut chunks);
+        }
+        chunks
+    }
+}
+
+impl CodeChunker {
+    /// Find the items that start at `indent` within lines `from..to`. Inside a type,
+    /// `parent` names it and only its members are items. Runs of code without a
+    /// symbol are kept together, and any code before the first item is included.
+    fn item_starts(&self, lines: &[&str], from: usize, to: usize, indent: usize, parent: Option<&str>) -> Vec<ItemStart> {
+        let mut items: Vec<ItemStart> = vec![ItemStart { line: from, symbol: None, owner: None }];
+        for i in from..to {
+            let trimmed = lines[i].trim();
+            if trimmed.is_empty() || indent_of(lines[i]) != indent {
+                continue;
+            }
+            let (symbol, owner) = match parent {
+                None => match item_symbol(self.language, trimmed) {
+                    Some(found) => found,
+                    None => continue,
+                },
+                Some(parent) => match member_name(self.language, trimmed) {
+                    Some(name) => (Some(format!("{}{}{}", parent, self.language.member_separator(), name)), None),
+                    None => continue,
+                },
+            };
+
+            // Pull comments and attributes above the item into it
+            let floor = items.last().map_or(from, |item| item.line);
+            let mut line = i;
+            while line > floor && indent_of(lines[line - 1]) == indent && self.language.is_preface(lines[line - 1].trim()) {
+                line -= 1;
+            }
+
+            let last = items.last_mut().expect("items starts non-empty");
+            if symbol.is_none() && last.symbol.is_none() {
+                continue;
+            }
+            if last.line == line && last.symbol.is_none() {
+                last.symbol = symbol;
+                last.owner = owner;
+            } else {
+                items.push(ItemStart { line, symbol, owner });
+            }
+        }
+        items
+    }
+
+    /// Chunk the item spanning from `item.line` up to line `end`.
+    fn chunk_item(&self, text: &str, lines: &[&str], line_starts: &[usize], item: &ItemStart, end: usize, chunks: &mut Vec<Chunk>) {
+        let (start_byte, end_byte) = (line_starts[item.line], line_starts[end]);
+        if start_byte == end_byte {
+            return;
+        }
+        let fits = self.options.unit.measure(&text[start_byte..end_byte]) <= self.options.size;
+
+        // Split a large type on its members, one level deep
+        if let (false, Some(owner)) = (fits, &item.owner) {
+            let member_indent = lines[item.line + 1..end]
+                .iter()
+                .find(|l| !l.trim().is_empty() && indent_of(l) > indent_of(lines[item.line]))
+                .map(|l| indent_of(l));
+            if let Some(member_indent) = member_indent {
+                let mut members = self.item_starts(lines, item.line, end, member_indent, Some(owner));
+                if members.len() > 1 {
+                    // Code before the first member is the type's own header
+                    members[0].symbol = item.symbol.clone();
+                    for (i, member) in members.iter().enumerate() {
+                        let member_end = members.get(i + 1).map_or(end, |next| next.line);
+                        let member = ItemStart { line: member.line, symbol: member.symbol.clone(), owner: None };
+                        self.chunk_item(text, lines, line_starts, &member, member_end, chunks);
+                    }
+                    return;
+                }
+            }
+        }
+
+        let mut pieces = Vec::new();
+        split_recursive(text, start_byte, end_byte, &RECURSIVE_SEPARATORS[..2], &self.options, &mut pieces);
+        for mut chunk in merge_pieces(text, &pieces, &self.options) {
+            chunk.symbol = item.symbol.clone();
+            chunks.push(chunk);
+        }
+    }
+}
+
+/// Width of a line's leading whitespace.
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// The leading identifier of `word`, as in `name` from `name<T>(`.
+fn identifier(word: &str) -> &str {
+    let end = word
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+        .unwrap_or(word.len());
+    &word[..end]
+}
+
+/// If a trimmed, unindented line starts an item, return its symbol (`None` for
+/// imports) and the type name whose members may be split out.
+fn item_symbol(language: Language, line: &str) -> Option<(Option<String>, Option<String>)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let named = |keyword: &str, i: usize| {
+        let name = identifier(words.get(i).copied().unwrap_or(""));
+        (!name.is_empty()).then(|| format!("{} {}", keyword, name))
+    };
+    match language {
+        Language::Rust => {
+            let mut i = 0;
+            while let Some(&word) = words.get(i) {
+                let next_is_fn = words.get(i + 1).is_some_and(|n| matches!(*n, "fn" | "unsafe" | "async" | "extern"));
+                let qualifier = word.starts_with("pub")
+                    || matches!(word, "async" | "unsafe" | "default" | "extern")
+                    || word.starts_with('"')
+                    || (word == "const" && next_is_fn);
+                if !qualifier {
+                    break;
+                }
+                i += 1;
+            }
+            match *words.get(i)? {
+                "use" | "crate" => Some((None, None)),
+                "trait" => {
+                    let symbol = named("trait", i + 1)?;
+                    Some((Some(symbol), Some(identifier(words[i + 1]).to_string())))
+                }
+                keyword @ ("fn" | "struct" | "enum" | "mod" | "type" | "const" | "static" | "union" | "macro_rules!") => {
+                    Some((Some(named(keyword, i + 1)?), None))
+                }
+                word if word == "impl" || word.starts_with("impl<") => {
+                    let header = line[line.find("impl")?..].split(['{', ';']).next()?;
+                    let header = header.split(" where").next()?.trim();
+                    Some((Some(header.to_string()), Some(impl_owner(header))))
+                }
+                _ => None,
+            }
+        }
+        Language::Python => {
+            let i = usize::from(words.first() == Some(&"async"));
+            match *words.get(i)? {
+                "import" | "from" => Some((None, None)),
+                "def" => Some((Some(named("def", i + 1)?), None)),
+                "class" => {
+                    let symbol = named("class", i + 1)?;
+                    Some((Some(symbol), Some(identifier(words[i + 1]).to_string())))
+                }
+                _ => None,
+            }
+        }
+        Language::TypeScript => {
+            let i = words
+                .iter()
+                .take_while(|w| matches!(**w, "export" | "default" | "declare" | "abstract" | "async"))
+                .count();
+            match *words.get(i)? {
+                "import" => Some((None, None)),
+                keyword @ ("function" | "function*") => Some((Some(named("function", i + 1).unwrap_or_else(|| keyword.to_string())), None)),
+                "class" => {
+                    let symbol = named("class", i + 1)?;
+                    Some((Some(symbol), Some(identifier(words[i + 1]).to_string())))
+                }
+                keyword @ ("interface" | "enum" | "namespace" | "type" | "const" | "let" | "var") => {
+                    Some((Some(named(keyword, i + 1)?), None))
+                }
+                _ => None,
+            }
+        }
+        Language::Go => match *words.first()? {
+            "package" | "import" => Some((None, None)),
+            "func" => {
+                let rest = line["func".len()..].trim_start();
+                // Methods are named after their receiver type, as in `Store.Save`
+                if let Some(receiver) = rest.strip_prefix('(') {
+                    let (receiver, name) = receiver.split_once(')')?;
+                    let ty = receiver.split_whitespace().last()?.trim_start_matches('*');
+                    let ty = identifier(ty);
+                    Some((Some(format!("func {}.{}", ty, identifier(name.trim_start()))), None))
+                } else {
+                    Some((Some(named("func", 1)?), None))
+                }
+            }
+            keyword @ ("type" | "var" | "const") => Some((named(keyword, 1), None)),
+            _ => None,
+        },
+    }
+}
+
+/// If a trimmed line inside a type starts a method, return the method's name.
+fn member_name(language: Language, line: &str) -> Option<String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let name = match language {
+        Language::Rust => {
+            let i = words
+                .iter()
+                .take_while(|w| w.starts_with("pub") || matches!(**w, "async" | "unsafe" | "const" | "default" | "extern") || w.starts_with('"'))
+                .count();
+            match *words.get(i)? {
+                "fn" | "type" | "const" => identifier(words.get(i + 1)?),
+                _ => return None,
+            }
+        }
+        Language::Python => {
+            let i = usize::from(words.first() == Some(&"async"));
+            if *words.get(i)? != "def" {
+                return None;
+            }
+            identifier(words.get(i + 1)?)
+        }
+        Language::TypeScript => {
+            let i = words
+                .iter()
+                .take_while(|w| matches!(**w, "public" | "private" | "protected" | "static" | "readonly" | "async" | "get" | "set" | "abstract" | "override"))
+                .count();
+            let word = words.get(i)?.trim_start_matches('#').trim_start_matches('*');
+            let name = identifier(word);
+            // Methods are followed by their parameter or type parameter list
+            if !word[name.len()..].starts_with(['(', '<']) {
+                return None;
+            }
+            name
+        }
+        Language::Go => return None,
+    };
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Name of the type an `impl` header is for, as in `Source` from `impl fmt::Display for Source`.
+fn impl_owner(header: &str) -> String {
+    let mut rest = header["impl".len()..].trim_start();
+    // Skip the impl's own generic parameters
+    if rest.starts_with('<') {
+        let mut depth = 0;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                rest = rest[i + 1..].trim_start();
+                break;
+            }
+        }
+    }
+    let ty = rest.rsplit_once(" for ").map_or(rest, |(_, ty)| ty).trim();
+    let ty = ty.split(['<', ' ']).next().unwrap_or(ty);
+    ty.rsplit("::").next().unwrap_or(ty).trim_start_matches('&').to_string()
+}
+
+/// 1-based first and last line of the byte range `start..end`, given the byte
+/// offsets of every newline in the text.
+pub fn line_range(newlines: &[usize], start: usize, end: usize) -> (usize, usize) {
+    let first = newlines.partition_point(|&n| n < start) + 1;
+    let last = newlines.partition_point(|&n| n < end.saturating_sub(1).max(start)) + 1;
+    (first, last)
+}
+
+/// Approximate the number of tokens a model's tokenizer would produce: every run of
+/// letters and digits counts one token per four characters, and every other
+/// non-space character is a token of its own.
+pub fn count_tokens(text: &str) -> usize {
+    let mut tokens = 0;
+    let mut word_len: usize = 0;
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            word_len += 1;
+            continue;
+        }
+        tokens += word_len.div_ceil(4);
+        word_len = 0;
+        if !c.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    tokens + word_len.div_ceil(4)
+}
+
+/// Byte ranges of the sentences in `text`. A sentence ends after `.`, `!` or `?`
+/// followed by whitespace, or at a blank line.
+fn sentence_spans(text: &str) -> Vec<(usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    for i in 0..bytes.len() {
+        let boundary = match bytes[i] {
+            b'.' | b'!' | b'?' => bytes.get(i + 1).is_some_and(|b| b.is_ascii_whitespace()),
+            b'\n' => bytes.get(i + 1) == Some(&b'\n'),
+            _ => false,
+        };
+        if boundary {
+            spans.push((start, i + 1));
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        spans.push((start, text.len()));
+    }
+    spans
+}
+
+/// Split `text[start..end]` into pieces no larger than the chunk size, using the
+/// first separator that occurs and recursing with the rest on pieces still too big.
+/// Separators stay attached to the piece before them, so the pieces cover the range
+/// exactly. Text with no separators left is split into characters.
+pub(crate) fn split_recursive(
+    text: &str,
+    start: usize,
+    end: usize,
+    separators: &[&str],
+    options: &ChunkOptions,
+    out: &mut Vec<(usize, usize)>,
+) {
+    if options.unit.measure(&text[start..end]) <= options.size {
+        out.push((start, end));
+        return;
+    }
+    match separators.split_first() {
+        Some((separator, rest)) => {
+            let mut piece_start = start;
+            for (i, _) in text[start..end].match_indices(separator) {
+                let piece_end = start + i + separator.len();
+                split_recursive(text, piece_start, piece_end, rest, options, out);
+                piece_start = piece_end;
+            }
+            if piece_start < end {
+                split_recursive(text, piece_start, end, rest, options, out);
+            }
+        }
+        None => {
+            for (i, c) in text[start..end].char_indices() {
+                out.push((start + i, start + i + c.len_utf8()));
+            }
+        }
+    }
+}
+
+/// Pack consecutive pieces into chunks of at most `options.size`, starting each
+/// chunk with trailing pieces of the previous one to make up `options.overlap`.
+pub(crate) fn merge_pieces(text: &str, pieces: &[(usize, usize)], options: &ChunkOptions) -> Vec<Chunk> {
+    let sizes: Vec<usize> = pieces
+        .iter()
+        .map(|&(start, end)| options.unit.measure(&text[start..end]))
+        .collect();
+
+    let mut chunks = Vec::new();
+    let mut first = 0;
+    while first < pieces.len() {
+        // Every chunk takes at least one piece, so oversized pieces still make progress
+        let mut last = first;
+        let mut total = sizes[first];
+        while last + 1 < pieces.len() && total + sizes[last + 1] <= options.size {
+            last += 1;
+            total += sizes[last];
+        }
+        push_chunk(text, pieces[first].0, pieces[last].1, &mut chunks);
+        if last + 1 == pieces.len() {
+            break;
+        }
+
+        // Step back over trailing pieces for the overlap, leaving room for the next piece
+        let mut next = last + 1;
+        let mut overlap = 0;
+        while next - 1 > first
+            && overlap + sizes[next - 1] <= options.overlap
+            && overlap + sizes[next - 1] + sizes[last + 1] <= options.size
+        {
+            next -= 1;
+            overlap += sizes[next];
+        }
+        first = next;
+    }
+    chunks
+}
+
+/// Add `text[start..end]` as a chunk with surrounding whitespace trimmed, unless nothing is left.
+pub(crate) fn push_chunk(text: &str, start: usize, end: usize, chunks: &mut Vec<Chunk>) {
+    let slice = &text[start..end];
+    let trimmed_start = start + (slice.len() - slice.trim_start().len());
+    let trimmed_end = start + slice.trim_end().len();
+    if trimmed_start < trimmed_end {
+        chunks.push(Chunk {
+            text: text[trimmed_start..trimmed_end].to_string(),
+            start: trimmed_start,
+            end: trimmed_end,
+            ..Chunk::default()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(size: usize, overlap: usize) -> ChunkOptions {
+        ChunkOptions { unit: SizeUnit::Characters, size, overlap }
+    }
+
+    /// Check the promises of `Chunker::chunk`: chunks are non-empty, trimmed, in
+    /// order, and their byte ranges point at their text.
+    fn assert_valid(text: &str, chunks: &[Chunk]) {
+        let mut previous_start = 0;
+        for chunk in chunks {
+            assert!(!chunk.text.trim().is_empty(), "empty chunk in {:?}", chunks);
+            assert_eq!(chunk.text, chunk.text.trim());
+            assert!(chunk.start < chunk.end && chunk.end <= text.len());
+            assert!(text.is_char_boundary(chunk.start) && text.is_char_boundary(chunk.end));
+            assert_eq!(&text[chunk.start..chunk.end], chunk.text);
+            assert!(chunk.start >= previous_start, "chunks out of order");
+            previous_start = chunk.start;
+        }
+    }
+
+    #[test]
+    fn empty_and_blank_input_give_no_chunks() {
+        let chunkers: Vec<Box<dyn Chunker>> = vec![
+            Box::new(RecursiveChunker { options: options(50, 10) }),
+            Box::new(SentenceChunker { options: options(50, 10) }),
+            Box::new(MarkdownChunker { options: options(50, 10) }),
+            Box::new(RecordChunker { options: options(50, 10) }),
+            Box::new(CodeChunker { options: options(50, 10), language: Language::Rust }),
+        ];
+        for chunker in &chunkers {
+            assert!(chunker.chunk("").is_empty());
+            assert!(chunker.chunk(" \n\n\t\n").is_empty());
+        }
+    }
+
+    #[test]
+    fn recursive_prefers_paragraphs_and_respects_size() {
+        let text = "First paragraph here.\n\nSecond paragraph here.\n\nThird paragraph here.";
+        let chunks = RecursiveChunker { options: options(30, 0) }.chunk(text);
+        assert_valid(text, &chunks);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["First paragraph here.", "Second paragraph here.", "Third paragraph here."]);
+    }
+
+    #[test]
+    fn recursive_overlaps_consecutive_chunks() {
+        let text = "one two three four five six seven eight nine ten";
+        let chunks = RecursiveChunker { options: options(20, 8) }.chunk(text);
+        assert_valid(text, &chunks);
+        assert!(chunks.len() > 1);
+        for pair in chunks.windows(2) {
+            assert!(pair[1].start < pair[0].end, "no overlap between {:?}", pair);
+        }
+        assert!(chunks.iter().all(|c| c.text.chars().count() <= 20));
+    }
+
+    #[test]
+    fn recursive_splits_multibyte_text_on_char_boundaries() {
+        let text = "日本語のテキストには空白がありません".repeat(3);
+        let chunks = RecursiveChunker { options: options(10, 3) }.chunk(&text);
+        assert_valid(&text, &chunks);
+        assert!(chunks.iter().all(|c| c.text.chars().count() <= 10));
+        assert_eq!(chunks.last().map(|c| c.end), Some(text.len()));
+    }
+
+    #[test]
+    fn sentence_keeps_sentences_whole() {
+        let text = "Short one. Another short one! A question? Last.";
+        let chunks = SentenceChunker { options: options(25, 0) }.chunk(text);
+        assert_valid(text, &chunks);
+        for chunk in &chunks {
+            assert!(chunk.text.ends_with(['.', '!', '?']), "split mid-sentence: {:?}", chunk.text);
+        }
+    }
+
+    #[test]
+    fn sentence_splits_oversize_sentences_on_words() {
+        let text = "This single sentence is much longer than the chunk size allows it to be.";
+        let chunks = SentenceChunker { options: options(20, 0) }.chunk(text);
+        assert_valid(text, &chunks);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| c.text.chars().count() <= 20));
+    }
+
+    #[test]
+    fn markdown_labels_chunks_with_heading_breadcrumbs() {
+        let text = "# Guide\n\nIntro.\n\n## Install\n\nRun it.\n\n### Linux\n\nUse apt.\n\n## Usage\n\nCall it.\n";
+        let chunks = MarkdownChunker { options: options(200, 0) }.chunk(text);
+        assert_valid(text, &chunks);
+        let headings: Vec<(&str, Option<&str>)> = chunks.iter().map(|c| (c.text.as_str(), c.heading.as_deref())).collect();
+        assert_eq!(
+            headings,
+            [
+                ("Intro.", Some("Guide")),
+                ("Run it.", Some("Guide > Install")),
+                ("Use apt.", Some("Guide > Install > Linux")),
+                ("Call it.", Some("Guide > Usage")),
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_keeps_oversize_code_blocks_whole() {
+        let code = "```rust\nfn main() {\n    println!(\"a line that is long\");\n}\n```";
+        let text = format!("# Example\n\nBefore.\n\n{}\n\nAfter.", code);
+        let chunks = MarkdownChunker { options: options(20, 0) }.chunk(&text);
+        assert_valid(&text, &chunks);
+        assert!(chunks.iter().any(|c| c.text == code), "code block was split: {:?}", chunks);
+    }
+
+    #[test]
+    fn markdown_runs_unclosed_fence_to_the_end() {
+        let text = "Text.\n\n```\nnever closed\n\n# not a heading";
+        let chunks = MarkdownChunker { options: options(100, 0) }.chunk(text);
+        assert_valid(text, &chunks);
+        assert!(chunks.iter().all(|c| c.heading.is_none()));
+    }
+
+    #[test]
+    fn records_keep_groups_apart() {
+        let text = "a: 1\nb: 2\n\na: 3\nb: 4\n\n";
+        let chunks = RecordChunker { options: options(100, 0) }.chunk(text);
+        assert_valid(text, &chunks);
+        let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["a: 1\nb: 2", "a: 3\nb: 4"]);
+    }
+
+    #[test]
+    fn code_chunks_carry_symbols() {
+        let text = "use std::fmt;\n\n/// Adds.\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nstruct Point {\n    x: i32,\n}\n";
+        let chunks = CodeChunker { options: options(60, 0), language: Language::Rust }.chunk(text);
+        assert_valid(text, &chunks);
+        let add = chunks.iter().find(|c| c.symbol.as_deref() == Some("fn add")).expect("fn add chunk");
+        assert!(add.text.starts_with("/// Adds."), "doc comment not kept with its item");
+        assert!(chunks.iter().any(|c| c.symbol.as_deref() == Some("struct Point")));
+    }
+
+    #[test]
+    fn code_splits_oversize_impls_on_methods() {
+        let text = "impl Point {\n    pub fn new() -> Self {\n        Point { x: 0 }\n    }\n\n    fn len(&self) -> i32 {\n        self.x\n    }\n}\n";
+        let chunks = CodeChunker { options: options(50, 0), language: Language::Rust }.chunk(text);
+        assert_valid(text, &chunks);
+        let symbols: Vec<Option<&str>> = chunks.iter().map(|c| c.symbol.as_deref()).collect();
+        assert!(symbols.contains(&Some("Point::new")), "{:?}", symbols);
+        assert!(symbols.contains(&Some("Point::len")), "{:?}", symbols);
+    }
+
+    #[test]
+    fn code_handles_multibyte_identifiers() {
+        let text = "def grüße():\n    return \"héllo wörld\"\n\ndef 日本():\n    pass\n";
+        let chunks = CodeChunker { options: options(20, 0), language: Language::Python }.chunk(text);
+        assert_valid(text, &chunks);
+        assert!(chunks.iter().any(|c| c.symbol.as_deref() == Some("def grüße")));
+    }
+
+    #[test]
+    fn line_range_counts_lines_of_a_byte_range() {
+        let text = "one\ntwo\nthree\n";
+        let newlines: Vec<usize> = text.match_indices('\n').map(|(i, _)| i).collect();
+        assert_eq!(line_range(&newlines, 0, 3), (1, 1));
+        assert_eq!(line_range(&newlines, 4, 13), (2, 3));
+        // A range ending just after a newline does not reach the next line
+        assert_eq!(line_range(&newlines, 0, 4), (1, 1));
+    }
+
+    #[test]
+    fn count_tokens_splits_long_words_and_punctuation() {
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(count_tokens("word"), 1);
+        assert_eq!(count_tokens("tokenizer"), 3);
+        assert_eq!(count_tokens("a, b."), 4);
+    }
+}
@@ -0,0 +1,380 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use clap::{Args, Parser, Subcommand};
+use serde_json::json;
+use crate::config::SearchFilter;
+use crate::embedding::embedder_from_env;
+use crate::files::{
+    DEFAULT_TOP_K, IndexProgress, SearchIndex, Source, cache_stats, index_status, indexed_dimension, prune_cache,
+    retrieve_context, setup_vector_store,
+};
+use crate::model::chat_model_from_env;
+use crate::ui::chat_interface::Message;
+
+/// Chat with the documents in a directory. Run without a command to open the interface.
+#[derive(Debug, Parser)]
+#[command(name = "fisher", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands that run without the interface, for use from scripts.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Build or update the index under <DIR>/.vs
+    Index {
+        dir: PathBuf,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the chunks nearest to a query with their scores
+    Query {
+        dir: PathBuf,
+        text: String,
+        /// Number of chunks to return
+        #[arg(short, default_value_t = DEFAULT_TOP_K)]
+        k: usize,
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Print the chunks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Answer a question from the indexed documents and list the sources used
+    Ask {
+        dir: PathBuf,
+        question: String,
+        /// Number of chunks to ground the answer in
+        #[arg(short, default_value_t = DEFAULT_TOP_K)]
+        k: usize,
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Print the answer and sources as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Report index size, files changed since indexing and the models used
+    Status {
+        dir: PathBuf,
+        /// Print the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect or prune the embedding cache used for <DIR>
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+/// Operations on the embedding cache.
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// Report the number of cached vectors, per model, and the cache size
+    Stats {
+        dir: PathBuf,
+        /// Print the stats as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove cached vectors that no chunk in the index uses
+    Prune {
+        dir: PathBuf,
+        /// Prune a cache shared through FISHER_EMBEDDING_CACHE, dropping the
+        /// vectors only other directories use
+        #[arg(long)]
+        all_directories: bool,
+    },
+}
+
+/// Conditions on the chunks a search may return. Unset options fall back to the
+/// `FISHER_MIN_SCORE`, `FISHER_SEARCH_GLOBS` and `FISHER_SEARCH_TYPES` variables.
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Leave out chunks less similar to the query than this score
+    #[arg(long, allow_negative_numbers = true)]
+    min_score: Option<f32>,
+    /// Only search files matching this glob, relative to <DIR>; may be repeated
+    #[arg(long = "glob")]
+    globs: Vec<String>,
+    /// Only search files with this extension; may be repeated
+    #[arg(long = "type")]
+    file_types: Vec<String>,
+    /// Only search files modified on or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    after: Option<u64>,
+    /// Only search files modified before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    before: Option<u64>,
+}
+
+impl FilterArgs {
+    fn into_filter(self) -> SearchFilter {
+        let mut filter = SearchFilter::from_env();
+        if self.min_score.is_some() {
+            filter.min_score = self.min_score;
+        }
+        if !self.globs.is_empty() {
+            filter.globs = self.globs;
+        }
+        if !self.file_types.is_empty() {
+            filter.file_types = self.file_types;
+        }
+        filter.modified_after = self.after;
+        filter.modified_before = self.before;
+        filter
+    }
+}
+
+/// Parse a `YYYY-MM-DD` date as seconds since the Unix epoch at midnight UTC.
+fn parse_date(date: &str) -> Result<u64, String> {
+    let invalid = || format!("'{}' is not a date of the form YYYY-MM-DD", date);
+    let mut parts = date.trim().splitn(3, '-').map(|p| p.parse::<u32>().map_err(|_| invalid()));
+    let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(y), Some(m), Some(d)) => (y?, m?, d?),
+        _ => return Err(invalid()),
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=month_days).contains(&day) {
+        return Err(invalid());
+    }
+    // Days from the epoch to the civil date, counting years from March so the leap day comes last
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::from(era) * 146_097 + u64::from(day_of_era) - 719_468;
+    Ok(days * 86_400)
+}
+
+/// Run a headless command, writing results to stdout and progress to stderr.
+pub async fn run(command: Command) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match command {
+        Command::Index { dir, json } => index(dir, json).await,
+        Command::Query { dir, text, k, filter, json } => query(dir, &text, k, &filter.into_filter(), json).await,
+        Command::Ask { dir, question, k, filter, json } => ask(dir, &question, k, &filter.into_filter(), json).await,
+        Command::Status { dir, json } => status(dir, json),
+        Command::Cache { command: CacheCommand::Stats { dir, json } } => cache(dir, json),
+        Command::Cache { command: CacheCommand::Prune { dir, all_directories } } => {
+            println!("Removed {} cached vector(s)", prune_cache(&dir, all_directories)?);
+            Ok(())
+        }
+    }
+}
+
+async fn index(dir: PathBuf, json: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let embedder = embedder_from_env(|model| indexed_dimension(&dir, model)).await?;
+
+    // Ctrl-C stops after the current file, keeping what was indexed so far
+    let cancel = Arc::new(AtomicBool::new(false));
+    let on_interrupt = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            on_interrupt.store(true, Ordering::Relaxed);
+        }
+    });
+
+    let progress = |p: IndexProgress| match p {
+        IndexProgress::Scanned { files, skipped } => {
+            eprintln!("Found {} file(s), skipped {}", files, skipped.len());
+        }
+        IndexProgress::FileIndexed { path, chunks, embedded, cached, .. } => {
+            eprintln!("{}: {} chunk(s), {} embedded, {} from cache", path.display(), chunks, embedded, cached);
+        }
+        IndexProgress::FileFailed { path, error } => {
+            eprintln!("{}: failed: {}", path.display(), error);
+        }
+        IndexProgress::Rebuilding { files_done: 0, files, .. } => {
+            eprintln!("Rebuilding the index from {} stored file(s)", files);
+        }
+        IndexProgress::Rebuilding { files_done, files, embedded, cached, .. } => {
+            eprintln!("Rebuilt {}/{}: {} embedded, {} from cache", files_done, files, embedded, cached);
+        }
+    };
+    let report = setup_vector_store(dir, embedder.as_ref(), &progress, &cancel).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("Indexed {} file(s)", report.files_indexed);
+        if report.cached > 0 {
+            println!("Reused {} cached vector(s)", report.cached);
+        }
+        for skipped in &report.skipped {
+            println!("Skipped {} ({})", skipped.path.display(), skipped.reason);
+        }
+        for failed in &report.failed {
+            println!("Failed {} ({})", failed.path.display(), failed.reason);
+        }
+        if report.cancelled {
+            println!("Cancelled before all files were indexed");
+        }
+    }
+    Ok(())
+}
+
+async fn query(
+    dir: PathBuf,
+    text: &str,
+    k: usize,
+    filter: &SearchFilter,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let embedder = embedder_from_env(|model| indexed_dimension(&dir, model)).await?;
+    let index = SearchIndex::open(&dir, embedder.dimension())?;
+    let hits = retrieve_context(text, &index, embedder.as_ref(), k, filter).await?;
+
+    if json {
+        let hits: Vec<_> = hits
+            .iter()
+            .map(|(text, source)| json!({ "source": source, "text": text }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+    } else {
+        for (i, (text, source)) in hits.iter().enumerate() {
+            println!("[{}] {}\n{}\n", i + 1, source, text.trim());
+        }
+    }
+    Ok(())
+}
+
+async fn ask(
+    dir: PathBuf,
+    question: &str,
+    k: usize,
+    filter: &SearchFilter,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let embedder = embedder_from_env(|model| indexed_dimension(&dir, model)).await?;
+    let chat_model = chat_model_from_env()?;
+    let index = SearchIndex::open(&dir, embedder.dimension())?;
+    let context = retrieve_context(question, &index, embedder.as_ref(), k, filter).await?;
+    let (texts, sources): (Vec<String>, Vec<Source>) = context.into_iter().unzip();
+
+    let messages = [Message {
+        sender: "User".to_string(),
+        content: question.to_string(),
+        sources: Vec::new(),
+        incomplete: false,
+    }];
+    let answer = chat_model.generate_response(&messages, &texts).await?;
+
+    if json {
+        let output = json!({
+            "answer": answer,
+            "model": chat_model.name(),
+            "sources": sources,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!("{}", answer.trim());
+        if !sources.is_empty() {
+            println!("\nSources:");
+            for (i, source) in sources.iter().enumerate() {
+                println!("[{}] {}", i + 1, source);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn status(dir: PathBuf, json: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let status = index_status(&dir)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+    match status.vectors {
+        Some(vectors) => println!(
+            "Index: {} vector(s) of dimension {} ({})",
+            vectors,
+            status.dimension.unwrap_or_default(),
+            status.index_description
+        ),
+        None => println!("Index: not built"),
+    }
+    println!("Chunks: {} from {} file(s)", status.chunks, status.files_indexed);
+    if !status.embedding_models.is_empty() {
+        println!("Embedding model: {}", status.embedding_models.join(", "));
+    }
+    for (label, files) in [("Stale", &status.stale), ("New", &status.new)] {
+        println!("{}: {}", label, files.len());
+        for file in files {
+            println!("  {}", file.display());
+        }
+    }
+    println!("Deleted: {}", status.deleted.len());
+    for file in &status.deleted {
+        println!("  {}", file);
+    }
+    Ok(())
+}
+
+fn cache(dir: PathBuf, json: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(stats) = cache_stats(&dir)? else {
+        println!("Embedding cache: off");
+        return Ok(());
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    println!("Embedding cache: {}", stats.path.display());
+    println!("Vectors: {} ({} bytes)", stats.entries, stats.bytes);
+    for (model, entries) in &stats.models {
+        println!("  {}: {}", model, entries);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_counts_days_from_the_epoch() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("1970-03-01"), Ok(59 * 86_400));
+        assert_eq!(parse_date("2000-02-29"), Ok(951_782_400));
+        assert_eq!(parse_date("2024-02-29"), Ok(1_709_164_800));
+        assert_eq!(parse_date(" 2024-12-31 "), Ok(1_735_603_200));
+    }
+
+    #[test]
+    fn parse_date_rejects_days_past_the_end_of_the_month() {
+        for date in ["2024-02-30", "2024-02-31", "2023-02-29", "2100-02-29", "2024-04-31", "2024-11-31", "2024-01-32"] {
+            assert!(parse_date(date).is_err(), "{} was accepted", date);
+        }
+    }
+
+    #[test]
+    fn parse_date_rejects_malformed_dates() {
+        for date in ["", "2024", "2024-05", "2024-00-10", "2024-13-01", "2024-05-00", "1969-12-31", "2024-05-1x", "24/05/01"] {
+            assert!(parse_date(date).is_err(), "{:?} was accepted", date);
+        }
+    }
+
+    #[test]
+    fn date_flags_become_the_filter_bounds() {
+        let cli = Cli::try_parse_from(["fisher", "query", "docs", "text", "--after", "2024-03-01", "--before", "2024-03-02"]).unwrap();
+        let Some(Command::Query { filter, .. }) = cli.command else {
+            panic!("not a query");
+        };
+        let filter = filter.into_filter();
+        assert_eq!(filter.modified_after, Some(1_709_251_200));
+        assert_eq!(filter.modified_before, Some(1_709_251_200 + 86_400));
+        assert!(Cli::try_parse_from(["fisher", "query", "docs", "text", "--after", "2024-02-31"]).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::chunking::ChunkOptions;
+use crate::faiss::{IndexKind, Metric};
+
+/// Read a comma-separated list from an environment variable, ignoring empty items.
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Parse an environment variable, falling back to `default` when unset or invalid.
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Encoding assumed for text files that are neither UTF-8 nor marked with a byte
+/// order mark, from `FISHER_ENCODING` (any WHATWG label such as `latin1` or
+/// `shift_jis`), defaulting to Windows-1252.
+pub fn fallback_encoding() -> &'static encoding_rs::Encoding {
+    env::var("FISHER_ENCODING")
+        .ok()
+        .and_then(|label| encoding_rs::Encoding::for_label(label.trim().as_bytes()))
+        .unwrap_or(encoding_rs::WINDOWS_1252)
+}
+
+/// Options controlling which files in a directory get indexed.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    /// Globs a file must match to be indexed; empty means every file.
+    pub include: Vec<String>,
+    /// Globs of files and directories to leave out.
+    pub exclude: Vec<String>,
+    /// Files larger than this many bytes are skipped.
+    pub max_file_size: u64,
+    /// Whether to index the targets of symbolic links.
+    pub follow_symlinks: bool,
+}
+
+impl WalkOptions {
+    /// Read the options from `FISHER_INCLUDE`, `FISHER_EXCLUDE`,
+    /// `FISHER_MAX_FILE_SIZE` and `FISHER_FOLLOW_SYMLINKS`.
+    pub fn from_env() -> Self {
+        let defaults = WalkOptions::default();
+        WalkOptions {
+            include: env_list("FISHER_INCLUDE"),
+            exclude: env_list("FISHER_EXCLUDE"),
+            max_file_size: env_parse("FISHER_MAX_FILE_SIZE", defaults.max_file_size),
+            follow_symlinks: env_parse("FISHER_FOLLOW_SYMLINKS", defaults.follow_symlinks),
+        }
+    }
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_file_size: 20 * 1024 * 1024,
+            follow_symlinks: false,
+        }
+    }
+}
+
+/// Directory holding the embedding cache for the index in `vs_dir`, from
+/// `FISHER_EMBEDDING_CACHE`: unset keeps it in `vs_dir`, a path shares one cache
+/// between directories, and `off` disables caching.
+pub fn embedding_cache_dir(vs_dir: &Path) -> Option<PathBuf> {
+    match env::var("FISHER_EMBEDDING_CACHE") {
+        Ok(dir) if dir.trim().eq_ignore_ascii_case("off") => None,
+        Ok(dir) if !dir.trim().is_empty() => Some(PathBuf::from(dir.trim())),
+        _ => Some(vs_dir.to_path_buf()),
+    }
+}
+
+/// Limits on requests to a remote embedding API.
+#[derive(Debug, Clone)]
+pub struct EmbeddingLimits {
+    /// Most texts sent in one request, below the provider's own cap; `None` uses the cap.
+    pub batch_size: Option<usize>,
+    /// Requests allowed per minute; `None` sends them as fast as they are allowed to go.
+    pub requests_per_minute: Option<u32>,
+    /// Requests allowed in flight at once, across all files.
+    pub concurrency: usize,
+    /// Times a request failing with a rate limit, server or network error is retried.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after it.
+    pub initial_backoff: Duration,
+}
+
+impl EmbeddingLimits {
+    /// Read the limits from `FISHER_EMBEDDING_BATCH_SIZE`, `FISHER_EMBEDDING_RPM`,
+    /// `FISHER_EMBEDDING_CONCURRENCY`, `FISHER_EMBEDDING_RETRIES` and
+    /// `FISHER_EMBEDDING_BACKOFF_MS`.
+    pub fn from_env() -> Self {
+        let defaults = EmbeddingLimits::default();
+        EmbeddingLimits {
+            batch_size: Some(env_parse("FISHER_EMBEDDING_BATCH_SIZE", 0)).filter(|&n| n > 0),
+            requests_per_minute: Some(env_parse("FISHER_EMBEDDING_RPM", 0)).filter(|&n| n > 0),
+            concurrency: env_parse("FISHER_EMBEDDING_CONCURRENCY", defaults.concurrency).max(1),
+            max_retries: env_parse("FISHER_EMBEDDING_RETRIES", defaults.max_retries),
+            initial_backoff: env::var("FISHER_EMBEDDING_BACKOFF_MS")
+                .ok()
+                .and_then(|ms| ms.trim().parse().ok())
+                .map_or(defaults.initial_backoff, Duration::from_millis),
+        }
+    }
+}
+
+impl Default for EmbeddingLimits {
+    fn default() -> Self {
+        Self {
+            batch_size: None,
+            requests_per_minute: None,
+            concurrency: 4,
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Options controlling how files are split into chunks.
+#[derive(Debug, Clone)]
+pub struct ChunkConfig {
+    pub options: ChunkOptions,
+    /// Chunker used for files without a strategy of their own.
+    pub default_strategy: String,
+    /// Chunker to use by lowercase file extension.
+    pub strategies: HashMap<String, String>,
+}
+
+impl ChunkConfig {
+    /// Read the options from `FISHER_CHUNK_SIZE`, `FISHER_CHUNK_OVERLAP`,
+    /// `FISHER_CHUNK_UNIT` (`chars` or `tokens`), `FISHER_CHUNKER` (`recursive`,
+    /// `sentence`, `markdown`, `code` or `records`) and `FISHER_CHUNKERS`, a list of
+    /// `extension=strategy` overrides. Markdown and source files in the languages
+    /// `code` understands use those strategies unless overridden, as do HTML, DOCX,
+    /// ODT and EPUB, whose headings are converted to Markdown. CSV and JSON files
+    /// use `records`, which keeps each group of records in its own chunk.
+    pub fn from_env() -> Self {
+        let defaults = ChunkConfig::default();
+        let mut strategies = defaults.strategies;
+        strategies.extend(env_list("FISHER_CHUNKERS").into_iter().filter_map(|item| {
+            let (extension, strategy) = item.split_once('=')?;
+            Some((
+                extension.trim().trim_start_matches('.').to_lowercase(),
+                strategy.trim().to_string(),
+            ))
+        }));
+        ChunkConfig {
+            options: ChunkOptions {
+                unit: env_parse("FISHER_CHUNK_UNIT", defaults.options.unit),
+                size: env_parse("FISHER_CHUNK_SIZE", defaults.options.size).max(1),
+                overlap: env_parse("FISHER_CHUNK_OVERLAP", defaults.options.overlap),
+            },
+            default_strategy: env::var("FISHER_CHUNKER").unwrap_or(defaults.default_strategy),
+            strategies,
+        }
+    }
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            options: ChunkOptions::default(),
+            default_strategy: "recursive".to_string(),
+            strategies: [
+                ("md", "markdown"),
+                ("markdown", "markdown"),
+                ("rs", "code"),
+                ("py", "code"),
+                ("pyi", "code"),
+                ("ts", "code"),
+                ("tsx", "code"),
+                ("mts", "code"),
+                ("cts", "code"),
+                ("js", "code"),
+                ("jsx", "code"),
+                ("mjs", "code"),
+                ("cjs", "code"),
+                ("go", "code"),
+                ("html", "markdown"),
+                ("htm", "markdown"),
+                ("xhtml", "markdown"),
+                ("docx", "markdown"),
+                ("odt", "markdown"),
+                ("epub", "markdown"),
+                ("csv", "records"),
+                ("tsv", "records"),
+                ("json", "records"),
+                ("jsonl", "records"),
+                ("ndjson", "records"),
+            ]
+            .into_iter()
+            .map(|(extension, strategy)| (extension.to_string(), strategy.to_string()))
+            .collect(),
+        }
+    }
+}
+
+/// Options controlling how CSV rows and JSON records are turned into text.
+#[derive(Debug, Clone)]
+pub struct RecordOptions {
+    /// Number of records kept together in one chunk.
+    pub group: usize,
+    /// Fields to keep, by header name or dotted JSON path; empty keeps every field.
+    pub fields: Vec<String>,
+}
+
+impl RecordOptions {
+    /// Read the options from `FISHER_RECORDS_PER_CHUNK` and `FISHER_RECORD_FIELDS`.
+    pub fn from_env() -> Self {
+        let defaults = RecordOptions::default();
+        RecordOptions {
+            group: env_parse("FISHER_RECORDS_PER_CHUNK", defaults.group).max(1),
+            fields: env_list("FISHER_RECORD_FIELDS"),
+        }
+    }
+
+    /// Whether the field called `name` is kept. Selecting a JSON path keeps the fields nested under it.
+    pub fn selects(&self, name: &str) -> bool {
+        self.fields.is_empty()
+            || self.fields.iter().any(|f| {
+                name.eq_ignore_ascii_case(f)
+                    || name.strip_prefix(f.as_str()).is_some_and(|rest| rest.starts_with('.'))
+            })
+    }
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        Self {
+            group: 1,
+            fields: Vec::new(),
+        }
+    }
+}
+
+/// Options controlling the FAISS index that chunks are searched with.
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    /// FAISS factory string such as `Flat`, `IVF1024,Flat`, `HNSW32` or `IVF256,PQ16`.
+    pub factory: String,
+    pub metric: Metric,
+    /// Vectors needed before an index that must be trained is built; until then a
+    /// flat index with the same metric is used.
+    pub min_training_vectors: usize,
+    /// Inverted lists an IVF index visits per search.
+    pub nprobe: Option<usize>,
+    /// Candidates an HNSW index keeps while searching.
+    pub ef_search: Option<usize>,
+}
+
+impl IndexConfig {
+    /// Read the options from `FISHER_INDEX`, `FISHER_METRIC` (`cosine`, `ip` or `l2`),
+    /// `FISHER_INDEX_MIN_TRAIN`, `FISHER_NPROBE` and `FISHER_EF_SEARCH`.
+    ///
+    /// Without `FISHER_INDEX_MIN_TRAIN`, IVF indexes wait for 39 vectors per list, the
+    /// fewest FAISS trains on without warning, and other trained indexes for 256.
+    pub fn from_env() -> Self {
+        let defaults = IndexConfig::default();
+        let factory = env::var("FISHER_INDEX")
+            .map(|f| f.trim().to_string())
+            .ok()
+            .filter(|f| !f.is_empty())
+            .unwrap_or(defaults.factory);
+        let lists = factory
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .find_map(|part| part.strip_prefix("IVF")?.parse::<usize>().ok());
+        IndexConfig {
+            min_training_vectors: env_parse("FISHER_INDEX_MIN_TRAIN", lists.map_or(256, |n| (39 * n).max(256))),
+            factory,
+            metric: env_parse("FISHER_METRIC", defaults.metric),
+            nprobe: env::var("FISHER_NPROBE").ok().and_then(|v| v.trim().parse().ok()),
+            ef_search: env::var("FISHER_EF_SEARCH").ok().and_then(|v| v.trim().parse().ok()),
+        }
+    }
+
+    /// The index to build for `vectors` vectors, wrapped in an IDMap so vectors are
+    /// addressed by chunk id. An index that needs training falls back to a flat one
+    /// while there are too few vectors to train it.
+    pub fn kind_for(&self, vectors: usize) -> IndexKind {
+        let factory = if self.factory.starts_with("IDMap") {
+            self.factory.clone()
+        } else {
+            format!("IDMap,{}", self.factory)
+        };
+        let kind = IndexKind { factory, metric: self.metric };
+        if kind.needs_training() && vectors < self.min_training_vectors {
+            return IndexKind { factory: "IDMap,Flat".to_string(), metric: self.metric };
+        }
+        kind
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            factory: "Flat".to_string(),
+            metric: Metric::Cosine,
+            min_training_vectors: 256,
+            nprobe: None,
+            ef_search: None,
+        }
+    }
+}
+
+/// Conditions a chunk must meet to be returned by a search.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    /// Lowest similarity to the query a chunk may have.
+    pub min_score: Option<f32>,
+    /// Globs, relative to the indexed directory, one of which the chunk's file must
+    /// match; empty allows every file.
+    pub globs: Vec<String>,
+    /// Extensions, without the dot, one of which the chunk's file must have; empty
+    /// allows every type.
+    pub file_types: Vec<String>,
+    /// Earliest modification time of the chunk's file, in seconds since the Unix epoch.
+    pub modified_after: Option<u64>,
+    /// Time the chunk's file must have been modified before, in seconds since the Unix epoch.
+    pub modified_before: Option<u64>,
+}
+
+impl SearchFilter {
+    /// Read the filter from `FISHER_MIN_SCORE`, `FISHER_SEARCH_GLOBS` and
+    /// `FISHER_SEARCH_TYPES`. Dates are only set by the command line.
+    pub fn from_env() -> Self {
+        SearchFilter {
+            min_score: env::var("FISHER_MIN_SCORE").ok().and_then(|v| v.trim().parse().ok()),
+            globs: env_list("FISHER_SEARCH_GLOBS"),
+            file_types: env_list("FISHER_SEARCH_TYPES"),
+            modified_after: None,
+            modified_before: None,
+        }
+    }
+
+    /// Whether the filter rejects any chunk on account of its file.
+    pub fn filters_files(&self) -> bool {
+        !self.globs.is_empty()
+            || !self.file_types.is_empty()
+            || self.modified_after.is_some()
+            || self.modified_before.is_some()
+    }
+}
+
+/// Share of the fused search ranking given to keyword (BM25) matches rather than
+/// vector matches, from `FISHER_KEYWORD_WEIGHT` between 0 (vectors only) and 1
+/// (keywords only), defaulting to an even split.
+pub fn keyword_weight() -> f32 {
+    env_parse("FISHER_KEYWORD_WEIGHT", 0.5f32).clamp(0.0, 1.0)
+}
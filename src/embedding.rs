@@ -0,0 +1,763 @@
+use async_trait::async_trait;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use reqwest::{Client, Response, StatusCode};
+use reqwest::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use crate::config::EmbeddingLimits;
+use crate::model::{Content, Part};
+
+/// Turns text into vectors for the index.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Name recorded with every chunk, so vectors from different models are never mixed.
+    fn model(&self) -> String;
+
+    /// Length of the vectors this embedder produces.
+    fn dimension(&self) -> usize;
+
+    /// Maximum number of texts accepted by one `embed_documents` call.
+    fn batch_limit(&self) -> usize;
+
+    /// Number of `embed_documents` calls worth running at once.
+    fn concurrency(&self) -> usize {
+        1
+    }
+
+    /// Embed chunks of documents for storage in the index.
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Embed a search query.
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Build the embedder selected by `FISHER_EMBEDDER` (`gemini`, `openai`, `ollama` or `hash`).
+///
+/// The HTTP backends read `FISHER_EMBEDDING_URL` and `FISHER_EMBEDDING_MODEL` and are
+/// wrapped in a `LimitedEmbedder` configured by `EmbeddingLimits::from_env`. When
+/// `FISHER_EMBEDDING_DIM` is not set, their dimension comes from `known_dimension`,
+/// given the model name, or else from a probe request sent within those limits.
+pub async fn embedder_from_env(
+    known_dimension: impl Fn(&str) -> Option<usize>,
+) -> Result<Box<dyn Embedder>, Box<dyn std::error::Error + Send + Sync>> {
+    let provider = env::var("FISHER_EMBEDDER").unwrap_or_else(|_| "gemini".to_string());
+    let dimension: Option<usize> = env::var("FISHER_EMBEDDING_DIM").ok().and_then(|d| d.parse().ok());
+    let model = env::var("FISHER_EMBEDDING_MODEL").ok();
+    let url = env::var("FISHER_EMBEDDING_URL").ok();
+
+    match provider.as_str() {
+        "gemini" => Ok(Box::new(LimitedEmbedder::new(GeminiEmbedder::new(), EmbeddingLimits::from_env()))),
+        "openai" => {
+            let embedder = OpenAiEmbedder {
+                client: Client::new(),
+                base_url: url.unwrap_or_else(|| "http://localhost:8080".to_string()),
+                model: model.ok_or("FISHER_EMBEDDING_MODEL not set in environment")?,
+                api_key: env::var("FISHER_EMBEDDING_API_KEY").ok(),
+                dimension: 0,
+            };
+            let mut embedder = LimitedEmbedder::new(embedder, EmbeddingLimits::from_env());
+            embedder.inner.dimension = match dimension.or_else(|| known_dimension(&embedder.model())) {
+                Some(d) => d,
+                None => embedder.embed_query("dimension probe").await?.len(),
+            };
+            Ok(Box::new(embedder))
+        }
+        "ollama" => {
+            let embedder = OllamaEmbedder {
+                client: Client::new(),
+                base_url: url.unwrap_or_else(|| "http://localhost:11434".to_string()),
+                model: model.unwrap_or_else(|| "nomic-embed-text".to_string()),
+                dimension: 0,
+            };
+            let mut embedder = LimitedEmbedder::new(embedder, EmbeddingLimits::from_env());
+            embedder.inner.dimension = match dimension.or_else(|| known_dimension(&embedder.model())) {
+                Some(d) => d,
+                None => embedder.embed_query("dimension probe").await?.len(),
+            };
+            Ok(Box::new(embedder))
+        }
+        "hash" => Ok(Box::new(HashEmbedder::new(dimension.unwrap_or(256)))),
+        other => Err(format!("Unknown embedder '{}'", other).into()),
+    }
+}
+
+/// Embed any number of texts, splitting them into batches the embedder accepts and
+/// sending up to `Embedder::concurrency` of them at once.
+pub async fn embed_in_batches(embedder: &dyn Embedder, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+    let batches: Vec<_> = texts.chunks(embedder.batch_limit().max(1)).map(|batch| async move {
+        let batch_embeddings = embedder.embed_documents(batch).await?;
+        if batch_embeddings.len() != batch.len() {
+            return Err(format!(
+                "Embedder returned {} vectors for {} texts",
+                batch_embeddings.len(),
+                batch.len()
+            ).into());
+        }
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(batch_embeddings)
+    }).collect();
+    let batches: Vec<Vec<Vec<f32>>> = stream::iter(batches)
+        .buffered(embedder.concurrency().max(1))
+        .try_collect()
+        .await?;
+    Ok(batches.into_iter().flatten().collect())
+}
+
+/// An embedding API answered with an error status.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// How long the server asked clients to wait before trying again.
+    pub retry_after: Option<Duration>,
+    pub message: String,
+}
+
+impl ApiError {
+    /// Whether the same request may succeed if sent again later.
+    fn is_transient(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS
+            || self.status == StatusCode::REQUEST_TIMEOUT
+            || self.status.is_server_error()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "API request failed ({}): {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Pass a successful response through, turning an error status into an `ApiError`.
+async fn check_status(response: Response) -> Result<Response, ApiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let message = response.text().await.unwrap_or_default();
+    Err(ApiError { status, retry_after, message })
+}
+
+/// Read a Retry-After value, which is either a number of seconds or an HTTP date.
+/// A date already in the past gives `None`.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value.trim())
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+/// Longest wait between retries when the server does not say how long to wait.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Wraps a remote embedder in the limits of an `EmbeddingLimits`: smaller batches,
+/// a requests-per-minute budget, a cap on requests in flight, and retries with
+/// exponential backoff and jitter for rate limits, server errors and dropped
+/// connections. A Retry-After header from the server takes precedence over the backoff.
+pub struct LimitedEmbedder<E> {
+    inner: E,
+    limits: EmbeddingLimits,
+    in_flight: Semaphore,
+    /// Earliest time the next request may start under the per-minute budget.
+    next_start: Mutex<Instant>,
+}
+
+impl<E: Embedder> LimitedEmbedder<E> {
+    pub fn new(inner: E, limits: EmbeddingLimits) -> Self {
+        Self {
+            inner,
+            in_flight: Semaphore::new(limits.concurrency.max(1)),
+            limits,
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Run `request` once a slot is free and the budget allows, retrying transient failures.
+    async fn send<T, F, Fut>(&self, request: F) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = {
+                let _permit = self.in_flight.acquire().await?;
+                self.wait_for_budget().await;
+                request().await
+            };
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            let retry_after = match retry_delay(error.as_ref()) {
+                Some(retry_after) if attempt < self.limits.max_retries => retry_after,
+                _ => return Err(error),
+            };
+            tokio::time::sleep(retry_after.unwrap_or_else(|| self.backoff(attempt))).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sleep until the next request fits in the per-minute budget, spacing requests evenly.
+    async fn wait_for_budget(&self) {
+        let Some(rpm) = self.limits.requests_per_minute else {
+            return;
+        };
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + Duration::from_secs(60) / rpm;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+
+    /// Delay before retry number `attempt + 1`: the initial backoff doubled per
+    /// attempt, capped at `MAX_BACKOFF`, then scaled by a random factor between
+    /// one half and one so that parallel requests do not retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self.limits.initial_backoff.saturating_mul(1 << attempt.min(16)).min(MAX_BACKOFF);
+        let random = (RandomState::new().hash_one(attempt) >> 11) as f64 / (1u64 << 53) as f64;
+        ceiling.mul_f64(0.5 + random / 2.0)
+    }
+}
+
+/// If a failed request is worth retrying, how long the server asked to wait, if it did.
+fn retry_delay(error: &(dyn std::error::Error + Send + Sync + 'static)) -> Option<Option<Duration>> {
+    if let Some(error) = error.downcast_ref::<ApiError>() {
+        return error.is_transient().then_some(error.retry_after);
+    }
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return (error.is_timeout() || error.is_connect() || error.is_request()).then_some(None);
+    }
+    None
+}
+
+#[async_trait]
+impl<E: Embedder> Embedder for LimitedEmbedder<E> {
+    fn model(&self) -> String {
+        self.inner.model()
+    }
+
+    fn dimension(&self) -> usize {
+        self.inner.dimension()
+    }
+
+    fn batch_limit(&self) -> usize {
+        let limit = self.inner.batch_limit();
+        self.limits.batch_size.map_or(limit, |size| size.min(limit))
+    }
+
+    fn concurrency(&self) -> usize {
+        self.limits.concurrency
+    }
+
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.send(|| self.inner.embed_documents(texts)).await
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        self.send(|| self.inner.embed_query(query)).await
+    }
+}
+
+// Gemini
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SingleEmbeddingRequest {
+    model: String,
+    content: Content,
+    #[serde(rename = "taskType")]
+    task_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchEmbeddingRequest {
+    requests: Vec<SingleEmbeddingRequest>,
+}
+
+/// Google's `gemini-embedding-001` through the Generative Language API.
+pub struct GeminiEmbedder {
+    client: Client,
+}
+
+impl GeminiEmbedder {
+    /// Gemini model used for document and query embeddings.
+    pub const MODEL: &'static str = "gemini-embedding-001";
+
+    pub fn new() -> Self {
+        Self { client: Client::new() }
+    }
+
+    fn url(method: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let api_key = env::var("GEMINI_API_KEY")
+            .map_err(|_| "GEMINI_API_KEY not set in environment")?;
+        Ok(format!(
+            "https://generativelanguage.googleapis.com/v1beta/models/{}:{}?key={}",
+            Self::MODEL, method, api_key
+        ))
+    }
+}
+
+#[async_trait]
+impl Embedder for GeminiEmbedder {
+    fn model(&self) -> String {
+        Self::MODEL.to_string()
+    }
+
+    fn dimension(&self) -> usize {
+        3072
+    }
+
+    fn batch_limit(&self) -> usize {
+        100
+    }
+
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        let requests: Vec<SingleEmbeddingRequest> = texts.iter().map(|t| SingleEmbeddingRequest {
+            model: format!("models/{}", Self::MODEL),
+            content: Content {
+                parts: vec![Part { text: t.clone() }],
+            },
+            task_type: "RETRIEVAL_DOCUMENT".to_string(),
+        }).collect();
+
+        let request_body = BatchEmbeddingRequest { requests };
+
+        let response = self.client
+            .post(Self::url("batchEmbedContents")?)
+            .header("Content-Type", "application/json")
+            .json(&request_body)
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+
+        let response_body: serde_json::Value = response.json().await?;
+        let mut embeddings = Vec::new();
+        if let Some(arr) = response_body.get("embeddings").and_then(|v| v.as_array()) {
+            for emb in arr {
+                if let Some(values) = emb.get("values") {
+                    embeddings.push(json_to_vector(values));
+                }
+            }
+        }
+        Ok(embeddings)
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        let request_body = serde_json::json!({
+            "model": format!("models/{}", Self::MODEL),
+            "content": {
+                "parts": [
+                    {"text": query}
+                ]
+            },
+            "taskType": "RETRIEVAL_QUERY"
+        });
+
+        let response = self.client
+            .post(Self::url("embedContent")?)
+            .header("Content-Type", "application/json")
+            .json(&request_body)
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+
+        let response_body: serde_json::Value = response.json().await?;
+        if let Some(values) = response_body.get("embedding").and_then(|e| e.get("values")) {
+            return Ok(json_to_vector(values));
+        }
+        Err("No embedding generated".into())
+    }
+}
+
+impl Default for GeminiEmbedder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// OpenAI-compatible servers (llama.cpp, vLLM, LocalAI, ...)
+
+/// Any server exposing OpenAI's `/v1/embeddings` endpoint.
+pub struct OpenAiEmbedder {
+    client: Client,
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+    dimension: usize,
+}
+
+impl OpenAiEmbedder {
+    async fn embed(&self, input: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = self.client
+            .post(format!("{}/v1/embeddings", self.base_url.trim_end_matches('/')))
+            .json(&serde_json::json!({ "model": self.model, "input": input }));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await?;
+        let response = check_status(response).await?;
+
+        // Results carry their input position and are not guaranteed to be in order
+        let response_body: serde_json::Value = response.json().await?;
+        let mut indexed: Vec<(u64, Vec<f32>)> = Vec::new();
+        if let Some(arr) = response_body.get("data").and_then(|v| v.as_array()) {
+            for (i, item) in arr.iter().enumerate() {
+                let index = item.get("index").and_then(|v| v.as_u64()).unwrap_or(i as u64);
+                if let Some(values) = item.get("embedding") {
+                    indexed.push((index, json_to_vector(values)));
+                }
+            }
+        }
+        indexed.sort_by_key(|(index, _)| *index);
+        Ok(indexed.into_iter().map(|(_, v)| v).collect())
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn model(&self) -> String {
+        format!("openai/{}", self.model)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn batch_limit(&self) -> usize {
+        64
+    }
+
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.embed(texts).await
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        self.embed(&[query.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| "No embedding generated".into())
+    }
+}
+
+// Ollama
+
+/// A local Ollama server's `/api/embed` endpoint.
+pub struct OllamaEmbedder {
+    client: Client,
+    base_url: String,
+    model: String,
+    dimension: usize,
+}
+
+impl OllamaEmbedder {
+    async fn embed(&self, input: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.client
+            .post(format!("{}/api/embed", self.base_url.trim_end_matches('/')))
+            .json(&serde_json::json!({ "model": self.model, "input": input }))
+            .send()
+            .await?;
+        let response = check_status(response).await?;
+
+        let response_body: serde_json::Value = response.json().await?;
+        let embeddings = response_body
+            .get("embeddings")
+            .and_then(|v| v.as_array())
+            .map(|arr| arr.iter().map(json_to_vector).collect())
+            .unwrap_or_default();
+        Ok(embeddings)
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    fn model(&self) -> String {
+        format!("ollama/{}", self.model)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn batch_limit(&self) -> usize {
+        64
+    }
+
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        self.embed(texts).await
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        self.embed(&[query.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| "No embedding generated".into())
+    }
+}
+
+// Offline hashing
+
+/// Deterministic bag-of-words embedder that needs no network.
+///
+/// Words are hashed into `dimension` buckets with a random-looking sign and the
+/// result is normalized, so texts sharing words end up close together. It is far
+/// weaker than a trained model but makes indexing work on air-gapped machines.
+pub struct HashEmbedder {
+    dimension: usize,
+}
+
+impl HashEmbedder {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension: dimension.max(1) }
+    }
+
+    fn embed(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0f32; self.dimension];
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+            let hash = fnv1a(word.to_lowercase().as_bytes());
+            let bucket = (hash % self.dimension as u64) as usize;
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[bucket] += sign;
+        }
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for x in &mut vector {
+                *x /= norm;
+            }
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl Embedder for HashEmbedder {
+    fn model(&self) -> String {
+        format!("hash-{}", self.dimension)
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn batch_limit(&self) -> usize {
+        usize::MAX
+    }
+
+    async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(texts.iter().map(|t| self.embed(t)).collect())
+    }
+
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.embed(query))
+    }
+}
+
+/// 64-bit FNV-1a, used because it is stable across Rust versions and platforms.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Convert a JSON array of numbers into a vector.
+fn json_to_vector(values: &serde_json::Value) -> Vec<f32> {
+    values
+        .as_array()
+        .map(|arr| arr.iter().filter_map(|x| x.as_f64().map(|f| f as f32)).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn api_error(status: u16, retry_after: Option<Duration>) -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(ApiError { status: StatusCode::from_u16(status).unwrap(), retry_after, message: String::new() })
+    }
+
+    /// Default limits with retries fast enough for tests.
+    fn quick_limits() -> EmbeddingLimits {
+        EmbeddingLimits { initial_backoff: Duration::from_millis(1), ..EmbeddingLimits::default() }
+    }
+
+    /// Answers with 429 until it has been called `failures` times, and records the
+    /// size of every batch it is sent.
+    #[derive(Default)]
+    struct FlakyEmbedder {
+        failures: usize,
+        calls: AtomicUsize,
+        batches: Mutex<Vec<usize>>,
+    }
+
+    #[async_trait]
+    impl Embedder for FlakyEmbedder {
+        fn model(&self) -> String {
+            "flaky".to_string()
+        }
+
+        fn dimension(&self) -> usize {
+            1
+        }
+
+        fn batch_limit(&self) -> usize {
+            3
+        }
+
+        async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(api_error(429, None));
+            }
+            self.batches.lock().unwrap().push(texts.len());
+            Ok(texts.iter().map(|t| vec![t.len() as f32]).collect())
+        }
+
+        async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(self.embed_documents(&[query.to_string()]).await?.remove(0))
+        }
+    }
+
+    #[test]
+    fn only_transient_failures_are_retried() {
+        assert_eq!(retry_delay(api_error(429, None).as_ref()), Some(None));
+        assert_eq!(retry_delay(api_error(500, None).as_ref()), Some(None));
+        let wait = Some(Duration::from_secs(2));
+        assert_eq!(retry_delay(api_error(503, wait).as_ref()), Some(wait));
+        assert_eq!(retry_delay(api_error(400, None).as_ref()), None);
+        assert_eq!(retry_delay(api_error(401, wait).as_ref()), None);
+        assert_eq!(retry_delay(Box::<dyn std::error::Error + Send + Sync>::from("bad input").as_ref()), None);
+    }
+
+    #[test]
+    fn retry_after_in_seconds_or_as_a_date() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(90));
+        let wait = parse_retry_after(&date).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90), "{:?}", wait);
+        assert_eq!(parse_retry_after("Thu, 01 Jan 1970 00:00:00 GMT"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_its_ceiling_with_jitter() {
+        let embedder = LimitedEmbedder::new(HashEmbedder::new(8), EmbeddingLimits::default());
+        for attempt in 0..20 {
+            let ceiling = Duration::from_secs(1 << attempt.min(6)).min(MAX_BACKOFF);
+            let delay = embedder.backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "attempt {}: {:?}", attempt, delay);
+        }
+        let delays: std::collections::HashSet<Duration> = (0..20).map(|_| embedder.backoff(3)).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn budget_spaces_requests_evenly() {
+        let limits = EmbeddingLimits { requests_per_minute: Some(6000), ..quick_limits() };
+        let embedder = LimitedEmbedder::new(HashEmbedder::new(8), limits);
+        let start = Instant::now();
+        for _ in 0..3 {
+            embedder.wait_for_budget().await;
+        }
+        // The first request goes at once, then one every 10ms
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_retried_until_they_succeed() {
+        let embedder = LimitedEmbedder::new(FlakyEmbedder { failures: 2, ..Default::default() }, quick_limits());
+        let texts = vec!["a".to_string(), "bb".to_string()];
+        let vectors = embedder.embed_documents(&texts).await.unwrap();
+        assert_eq!(vectors, [vec![1.0], vec![2.0]]);
+        assert_eq!(embedder.inner.calls.load(Ordering::SeqCst), 3);
+
+        let limits = EmbeddingLimits { max_retries: 1, ..quick_limits() };
+        let embedder = LimitedEmbedder::new(FlakyEmbedder { failures: 2, ..Default::default() }, limits);
+        let error = embedder.embed_documents(&texts).await.unwrap_err();
+        assert!(error.downcast_ref::<ApiError>().is_some());
+        assert_eq!(embedder.inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn large_inputs_are_split_into_batches() {
+        let limits = EmbeddingLimits { batch_size: Some(2), ..quick_limits() };
+        let embedder = LimitedEmbedder::new(FlakyEmbedder::default(), limits);
+        assert_eq!(embedder.batch_limit(), 2);
+        let texts: Vec<String> = (1..=5).map(|n| "x".repeat(n)).collect();
+        let vectors = embed_in_batches(&embedder, &texts).await.unwrap();
+        assert_eq!(vectors, (1..=5).map(|n| vec![n as f32]).collect::<Vec<_>>());
+        let mut batches = embedder.inner.batches.lock().unwrap().clone();
+        batches.sort_unstable();
+        assert_eq!(batches, [1, 2, 2]);
+
+        // Without a configured size the inner embedder's own limit applies
+        let embedder = LimitedEmbedder::new(FlakyEmbedder::default(), quick_limits());
+        embed_in_batches(&embedder, &texts).await.unwrap();
+        let mut batches = embedder.inner.batches.lock().unwrap().clone();
+        batches.sort_unstable();
+        assert_eq!(batches, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn hash_embeddings_are_stable_and_distinct() {
+        let embedder = HashEmbedder::new(64);
+        assert_eq!(embedder.dimension(), 64);
+        assert_eq!(embedder.model(), "hash-64");
+        assert_eq!(HashEmbedder::new(0).dimension(), 1);
+
+        let texts = vec!["the quick brown fox".to_string(), "a lazy dog".to_string()];
+        let first = embedder.embed_documents(&texts).await.unwrap();
+        let second = HashEmbedder::new(64).embed_documents(&texts).await.unwrap();
+        assert_eq!(first, second);
+        assert!(first.iter().all(|v| v.len() == 64));
+        assert_ne!(first[0], first[1]);
+        assert_eq!(embedder.embed_query(&texts[0]).await.unwrap(), first[0]);
+        // Case and punctuation do not change the words that are hashed
+        assert_eq!(embedder.embed_query("The quick, brown FOX!").await.unwrap(), first[0]);
+    }
+
+    #[tokio::test]
+    async fn an_indexed_dimension_is_reused_instead_of_probing() {
+        // The only test touching these variables, so setting them cannot race another test.
+        // Nothing listens on the discard port, so a probe would fail without retries.
+        unsafe {
+            env::set_var("FISHER_EMBEDDER", "openai");
+            env::set_var("FISHER_EMBEDDING_MODEL", "test-model");
+            env::set_var("FISHER_EMBEDDING_URL", "http://127.0.0.1:9");
+            env::set_var("FISHER_EMBEDDING_RETRIES", "0");
+            env::remove_var("FISHER_EMBEDDING_DIM");
+        }
+        let known = embedder_from_env(|model| (model == "openai/test-model").then_some(768)).await;
+        let probed = embedder_from_env(|_| None).await;
+        unsafe {
+            for name in ["FISHER_EMBEDDER", "FISHER_EMBEDDING_MODEL", "FISHER_EMBEDDING_URL", "FISHER_EMBEDDING_RETRIES"] {
+                env::remove_var(name);
+            }
+        }
+
+        let embedder = known.unwrap();
+        assert_eq!(embedder.model(), "openai/test-model");
+        assert_eq!(embedder.dimension(), 768);
+        assert!(probed.is_err());
+    }
+}
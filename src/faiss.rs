@@ -1,27 +1,314 @@
-use faiss::{Index, index_factory, MetricType, index::IndexImpl};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use faiss::{Idx, Index, index_factory, MetricType, index::IndexImpl, read_index, write_index};
+use faiss::index::autotune::ParameterSpace;
+use faiss::selector::IdSelector;
+
+/// How vectors are compared when searching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Cosine similarity, as the inner product of vectors normalized to unit length.
+    Cosine,
+    /// Inner product of the vectors as they are.
+    InnerProduct,
+    /// Euclidean distance, reported as cosine similarity on the assumption that the
+    /// embedder returns unit-length vectors.
+    L2,
+}
+
+impl Metric {
+    fn metric_type(self) -> MetricType {
+        match self {
+            Metric::Cosine | Metric::InnerProduct => MetricType::InnerProduct,
+            Metric::L2 => MetricType::L2,
+        }
+    }
+
+    /// Convert a distance returned by a search into a similarity, higher meaning closer.
+    pub fn similarity(self, distance: f32) -> f32 {
+        match self {
+            Metric::Cosine | Metric::InnerProduct => distance,
+            // Squared L2 distance between unit vectors is 2 - 2cos
+            Metric::L2 => 1.0 - distance / 2.0,
+        }
+    }
+}
+
+impl std::str::FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "cosine" | "cos" => Ok(Metric::Cosine),
+            "ip" | "inner_product" | "dot" => Ok(Metric::InnerProduct),
+            "l2" => Ok(Metric::L2),
+            other => Err(format!("Unknown metric '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Metric::Cosine => "cosine",
+            Metric::InnerProduct => "ip",
+            Metric::L2 => "l2",
+        })
+    }
+}
+
+/// The FAISS factory string and metric of an index, written as `IDMap,HNSW32 (cosine)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexKind {
+    pub factory: String,
+    pub metric: Metric,
+}
+
+impl IndexKind {
+    /// Read a description written by `Display`. Indexes from before the metric was
+    /// configurable are described by their factory string alone and use L2.
+    pub fn from_description(description: &str) -> Self {
+        let parsed = description
+            .strip_suffix(')')
+            .and_then(|d| d.rsplit_once(" ("))
+            .and_then(|(factory, metric)| Some((factory, metric.parse().ok()?)));
+        match parsed {
+            Some((factory, metric)) => IndexKind { factory: factory.to_string(), metric },
+            None => IndexKind { factory: description.to_string(), metric: Metric::L2 },
+        }
+    }
+
+    /// Whether indexes of this kind must be trained on sample vectors before use.
+    pub fn needs_training(&self) -> bool {
+        ["IVF", "PQ", "SQ"].iter().any(|part| self.factory.contains(part))
+    }
+
+    /// Whether vectors can be removed from indexes of this kind; HNSW graphs do not allow it.
+    pub fn supports_removal(&self) -> bool {
+        !self.factory.contains("HNSW")
+    }
+}
+
+impl fmt::Display for IndexKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.factory, self.metric)
+    }
+}
+
+/// Why a `VectorStore` operation failed.
+#[derive(Debug)]
+pub enum VectorStoreError {
+    /// A vector, or a buffer of vectors, does not match the dimension of the index.
+    Dimension { expected: usize, found: usize },
+    /// The number of ids differs from the number of vectors they label.
+    IdCount { vectors: usize, ids: usize },
+    /// A saved index cannot be used as asked, such as after switching embedding models.
+    Incompatible { path: PathBuf, reason: String },
+    /// The kind of index cannot do what was asked, such as removing vectors from an HNSW graph.
+    Unsupported { factory: String, operation: &'static str },
+    Faiss(faiss::error::Error),
+}
+
+impl fmt::Display for VectorStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorStoreError::Dimension { expected, found } => {
+                write!(f, "Vector has dimension {}, the index expects {}", found, expected)
+            }
+            VectorStoreError::IdCount { vectors, ids } => write!(f, "Got {} ids for {} vectors", ids, vectors),
+            VectorStoreError::Incompatible { path, reason } => write!(f, "Index at {} {}", path.display(), reason),
+            VectorStoreError::Unsupported { factory, operation } => write!(f, "{} indexes do not support {}", factory, operation),
+            VectorStoreError::Faiss(e) => write!(f, "FAISS error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VectorStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VectorStoreError::Faiss(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<faiss::error::Error> for VectorStoreError {
+    fn from(e: faiss::error::Error) -> Self {
+        VectorStoreError::Faiss(e)
+    }
+}
 
 pub struct VectorStore {
     index: IndexImpl,
     dim: usize,
+    kind: IndexKind,
 }
 
 impl VectorStore {
-    pub fn new(dim: usize) -> faiss::error::Result<Self> {
-        let index = index_factory(dim as u32, "Flat", MetricType::L2)?;
-        Ok(VectorStore { index, dim })
+    pub fn new(dim: usize, kind: &IndexKind) -> Result<Self, VectorStoreError> {
+        let index = index_factory(dim as u32, &kind.factory, kind.metric.metric_type())?;
+        Ok(VectorStore { index, dim, kind: kind.clone() })
     }
-    pub fn add(&mut self, vectors: &[Vec<f32>]) -> faiss::error::Result<()> {
-        for v in vectors {
-            assert_eq!(v.len(), self.dim, "Vector has wrong dimension");
-            self.index.add(&v)?;
+
+    /// Load an index of the given kind previously written with `save`, checking it
+    /// has the expected dimension.
+    pub fn load(path: &Path, dim: usize, kind: &IndexKind) -> Result<Self, VectorStoreError> {
+        let store = Self::open(path, kind)?;
+        let incompatible = |reason: String| VectorStoreError::Incompatible { path: path.to_path_buf(), reason };
+        if store.dim != dim {
+            return Err(incompatible(format!("has dimension {}, expected {}", store.dim, dim)));
+        }
+        if store.index.metric_type() != kind.metric.metric_type() {
+            return Err(incompatible(format!("does not use the {} metric", kind.metric)));
+        }
+        Ok(store)
+    }
+
+    /// Load an index of the given kind previously written with `save`, whatever its dimension.
+    pub fn open(path: &Path, kind: &IndexKind) -> Result<Self, VectorStoreError> {
+        let index = read_index(path.to_string_lossy())?;
+        let dim = index.d() as usize;
+        Ok(VectorStore { index, dim, kind: kind.clone() })
+    }
+
+    /// Write the index to disk so it can be reloaded with `load`.
+    pub fn save(&self, path: &Path) -> Result<(), VectorStoreError> {
+        Ok(write_index(&self.index, path.to_string_lossy())?)
+    }
+
+    /// The factory string and metric the index was built with.
+    pub fn kind(&self) -> &IndexKind {
+        &self.kind
+    }
+
+    /// Train the index on sample vectors if its kind needs training and it has not been trained yet.
+    pub fn train(&mut self, vectors: &[Vec<f32>]) -> Result<(), VectorStoreError> {
+        if self.index.is_trained() {
+            return Ok(());
+        }
+        let buffer = self.flatten(vectors)?;
+        Ok(self.index.train(&buffer)?)
+    }
+
+    /// Set how widely searches look: `nprobe` lists for IVF indexes and `ef_search`
+    /// candidates for HNSW indexes. Settings that do not apply to the index are ignored.
+    pub fn set_search_parameters(&mut self, nprobe: Option<usize>, ef_search: Option<usize>) -> Result<(), VectorStoreError> {
+        let parameters = [
+            ("nprobe", nprobe.filter(|_| self.kind.factory.contains("IVF"))),
+            ("efSearch", ef_search.filter(|_| self.kind.factory.contains("HNSW"))),
+        ];
+        let space = ParameterSpace::new()?;
+        for (name, value) in parameters {
+            if let Some(value) = value {
+                space.set_index_parameter(&mut self.index, name, value as f64)?;
+            }
         }
         Ok(())
     }
 
-    pub fn query(&mut self, query: &[f32], k: usize) -> faiss::error::Result<(Vec<f32>, Vec<faiss::Idx>)> {
-        assert_eq!(query.len(), self.dim, "Query vector has wrong dimension");
-        let result = self.index.search(query, k)?;
-        Ok((result.distances, result.labels))
+    /// Add vectors under the given ids, which must not already be in the index.
+    pub fn add_with_ids(&mut self, vectors: &[Vec<f32>], ids: &[u64]) -> Result<(), VectorStoreError> {
+        let buffer = self.flatten(vectors)?;
+        self.add_batch(&buffer, ids)
+    }
+
+    /// Add the vectors laid out one after another in `vectors` under the given ids,
+    /// which must not already be in the index, in a single call to FAISS.
+    pub fn add_batch(&mut self, vectors: &[f32], ids: &[u64]) -> Result<(), VectorStoreError> {
+        let count = self.count_rows(vectors)?;
+        if count != ids.len() {
+            return Err(VectorStoreError::IdCount { vectors: count, ids: ids.len() });
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let buffer = self.prepare(vectors);
+        let ids: Vec<Idx> = ids.iter().map(|&id| Idx::new(id)).collect();
+        Ok(self.index.add_with_ids(&buffer, &ids)?)
+    }
+
+    /// Remove the vectors with the given ids, returning how many were removed.
+    /// Indexes that do not support removal report `VectorStoreError::Unsupported`.
+    pub fn remove_ids(&mut self, ids: &[u64]) -> Result<usize, VectorStoreError> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        if !self.kind.supports_removal() {
+            return Err(VectorStoreError::Unsupported { factory: self.kind.factory.clone(), operation: "removing vectors" });
+        }
+        let ids: Vec<Idx> = ids.iter().map(|&id| Idx::new(id)).collect();
+        let selector = IdSelector::batch(&ids)?;
+        Ok(self.index.remove_ids(&selector)?)
+    }
+
+    /// Ids and distances of the `k` vectors nearest to `query`, nearest first.
+    pub fn query(&mut self, query: &[f32], k: usize) -> Result<Vec<(u64, f32)>, VectorStoreError> {
+        Ok(self.search_batch(query, k)?.pop().unwrap_or_default())
+    }
+
+    /// Search for the queries laid out one after another in `queries` in a single
+    /// call to FAISS, returning the ids and distances of the `k` nearest vectors to
+    /// each, nearest first. Fewer are returned when the index holds fewer than `k`.
+    pub fn search_batch(&mut self, queries: &[f32], k: usize) -> Result<Vec<Vec<(u64, f32)>>, VectorStoreError> {
+        let count = self.count_rows(queries)?;
+        if count == 0 || k == 0 {
+            return Ok(vec![Vec::new(); count]);
+        }
+        let buffer = self.prepare(queries);
+        let result = self.index.search(&buffer, k)?;
+        Ok(result
+            .labels
+            .chunks(k)
+            .zip(result.distances.chunks(k))
+            .map(|(labels, distances)| {
+                labels
+                    .iter()
+                    .zip(distances)
+                    .filter_map(|(label, &distance)| Some((label.get()?, distance)))
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Number of vectors in a buffer of them laid out one after another.
+    fn count_rows(&self, buffer: &[f32]) -> Result<usize, VectorStoreError> {
+        // A buffer that does not divide into whole vectors is reported by its length
+        if !buffer.len().is_multiple_of(self.dim) {
+            return Err(VectorStoreError::Dimension { expected: self.dim, found: buffer.len() });
+        }
+        Ok(buffer.len() / self.dim)
+    }
+
+    /// Lay vectors out one after another, checking each has the index's dimension.
+    fn flatten(&self, vectors: &[Vec<f32>]) -> Result<Vec<f32>, VectorStoreError> {
+        let mut buffer = Vec::with_capacity(vectors.len() * self.dim);
+        for v in vectors {
+            if v.len() != self.dim {
+                return Err(VectorStoreError::Dimension { expected: self.dim, found: v.len() });
+            }
+            buffer.extend_from_slice(v);
+        }
+        Ok(buffer)
+    }
+
+    /// A buffer of vectors as stored in the index: each scaled to unit length for
+    /// cosine similarity.
+    fn prepare(&self, buffer: &[f32]) -> Vec<f32> {
+        let mut buffer = buffer.to_vec();
+        if self.kind.metric == Metric::Cosine {
+            for vector in buffer.chunks_mut(self.dim) {
+                let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    vector.iter_mut().for_each(|x| *x /= norm);
+                }
+            }
+        }
+        buffer
+    }
+
+    /// Get the dimension of the vectors in the store.
+    pub fn dimension(&self) -> usize {
+        self.dim
     }
 
     /// Get the number of vectors in the store.
@@ -29,4 +316,3 @@ impl VectorStore {
         self.index.ntotal() as usize
     }
 }
-
@@ -1,187 +1,1251 @@
-use std::path::PathBuf;
-use std::fs::{File, OpenOptions, read_to_string, read};
-use std::io::{Write, BufRead, BufReader};
-use crate::faiss::VectorStore;
-use crate::model::generate_embedding_document;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::fs::{File, read_to_string, read};
+use std::io::Read;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+use futures_util::future::join_all;
+use ignore::WalkBuilder;
+use ignore::overrides::{Override, OverrideBuilder};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::chunking::{chunker_for, chunking_fingerprint, line_range};
+use crate::loaders::{HEAD_LEN, looks_binary, registry};
+use crate::cache::{CacheStats, EmbeddingCache};
+use crate::config::{ChunkConfig, IndexConfig, SearchFilter, WalkOptions, embedding_cache_dir, keyword_weight};
+use crate::faiss::{IndexKind, VectorStore, VectorStoreError};
+use crate::keyword::{KEYWORD_FILE_NAME, KeywordIndex};
+use crate::metadata::{ChunkRecord, ChunkStore, read_embedding, read_legacy_lookup, retire_legacy_lookup};
+use crate::embedding::{Embedder, GeminiEmbedder, embed_in_batches};
 
+/// File name of the persisted FAISS index inside `.vs/`.
+const INDEX_FILE_NAME: &str = "faiss.index";
 
+/// Per-directory ignore file, using `.gitignore` syntax.
+const IGNORE_FILE_NAME: &str = ".fisherignore";
 
-pub async fn setup_vector_store(directory: PathBuf) {
+/// Constant added to ranks in reciprocal rank fusion, damping the lead of the top few results.
+const RRF_K: f32 = 60.0;
+
+/// Number of chunks retrieved to answer a question.
+pub const DEFAULT_TOP_K: usize = 5;
+
+/// Progress reported while a directory is indexed.
+#[derive(Debug, Clone)]
+pub enum IndexProgress {
+    /// The directory scan finished and `files` files will be checked.
+    Scanned { files: usize, skipped: Vec<SkippedFile> },
+    /// A file is up to date; `embedded` of its `chunks` had to be sent to the embedder
+    /// using `api_calls` requests, and `cached` were found in the embedding cache.
+    FileIndexed { path: PathBuf, chunks: usize, embedded: usize, cached: usize, api_calls: usize },
+    /// A file could not be indexed and was left out.
+    FileFailed { path: PathBuf, error: String },
+    /// The index is being rebuilt from the stored chunks, and `files_done` of the
+    /// `files` stored files have their vectors again. The counts are for the latest file.
+    Rebuilding { files_done: usize, files: usize, embedded: usize, cached: usize, api_calls: usize },
+}
+
+/// Outcome of one indexing run.
+#[derive(Debug, Default, Serialize)]
+pub struct IndexReport {
+    pub files_indexed: usize,
+    /// Chunks whose vectors came from the embedding cache rather than the embedder.
+    pub cached: usize,
+    pub skipped: Vec<SkippedFile>,
+    pub failed: Vec<SkippedFile>,
+    /// Whether the run stopped early because it was cancelled.
+    pub cancelled: bool,
+}
+
+/// Build or update the vector store for `directory`, persisting it under `.vs/`.
+///
+/// Files whose contents changed since they were indexed are re-chunked and only
+/// chunks with new text are embedded; files that no longer exist are dropped.
+/// Progress is passed to `progress` as it happens. Setting `cancel` stops the run
+/// after the current file, keeping the work done so far.
+pub async fn setup_vector_store(
+    directory: PathBuf,
+    embedder: &dyn Embedder,
+    progress: &(dyn Fn(IndexProgress) + Send + Sync),
+    cancel: &AtomicBool,
+) -> Result<IndexReport, Box<dyn std::error::Error + Send + Sync>> {
     let vs_dir = directory.join(".vs");
 
     if !vs_dir.exists() {
-        if let Err(e) = std::fs::create_dir_all(&vs_dir) {
-            eprintln!("Failed to create .vs directory: {}", e);
-        }
-    } 
-
-    let files = get_files(directory.clone());
-    // Read all file names from faiss_lookup.txt
-    let faiss_lookup_path = directory.join(".vs").join("faiss_lookup.txt");
-    let mut existing_files = Vec::new();
-    let mut is_empty = true;
-    if faiss_lookup_path.exists() {
-        let file = File::open(&faiss_lookup_path).unwrap();
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let line = line.unwrap();
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() == 2 {
-                existing_files.push(parts[0].to_string());
-                is_empty = false;
+        std::fs::create_dir_all(&vs_dir)?;
+    }
+
+    let scan = get_files(&directory, &WalkOptions::from_env())?;
+    let chunking = ChunkConfig::from_env();
+    let files = scan.files;
+    let mut report = IndexReport { skipped: scan.skipped, ..IndexReport::default() };
+    progress(IndexProgress::Scanned { files: files.len(), skipped: report.skipped.clone() });
+
+    let mut store = ChunkStore::open(&vs_dir)?;
+    if store.records().is_empty() {
+        migrate_legacy_lookup(&vs_dir, &mut store)?;
+    }
+    let cache = open_cache(&vs_dir)?;
+    let index_config = IndexConfig::from_env();
+    // Without the cache, every rebuild of an index that cannot remove vectors would
+    // send all of the chunks to the embedder again
+    let configured_kind = index_config.kind_for(usize::MAX);
+    if cache.is_none() && !configured_kind.supports_removal() {
+        return Err(format!(
+            "{} indexes cannot remove vectors and are rebuilt from the embedding cache when files change, \
+             so they cannot be used with FISHER_EMBEDDING_CACHE=off",
+            configured_kind.factory
+        )
+        .into());
+    }
+
+    // Reuse the saved index only if it still lines up with the metadata store and
+    // the configured index type, otherwise re-embed the stored chunks into a fresh index.
+    let index_path = vs_dir.join(INDEX_FILE_NAME);
+    let saved_kind = IndexKind::from_description(store.index_description());
+    let mut changed = false;
+    let mut vector_store = match VectorStore::load(&index_path, embedder.dimension(), &saved_kind) {
+        Ok(vs) if index_matches_store(&vs, &store, embedder, &index_config) => vs,
+        _ => {
+            changed = true;
+            match rebuild_index(&mut store, embedder, cache.as_ref(), &index_config, progress, cancel).await? {
+                Some(vs) => vs,
+                None => {
+                    report.cancelled = true;
+                    return Ok(report);
+                }
+            }
+        }
+    };
+
+    // Drop files that were deleted since the last run
+    let file_names: HashSet<String> = files.iter().map(|f| f.to_string_lossy().to_string()).collect();
+    for indexed in store.files() {
+        if !file_names.contains(&indexed) {
+            let removed = store.remove_file(&indexed);
+            let ids: Vec<u64> = removed.iter().map(|r| r.id).collect();
+            remove_vectors(&mut vector_store, &ids)?;
+            changed = true;
+        }
+    }
+
+    // Files are prepared one at a time, then up to `concurrency` of them are embedded together
+    for window in files.chunks(embedder.concurrency().max(1)) {
+        if cancel.load(Ordering::Relaxed) {
+            report.cancelled = true;
+            break;
+        }
+        let mut outcomes = Vec::new();
+        let mut pending = Vec::new();
+        for file in window {
+            match prepare_file(file, &chunking, &mut store, embedder) {
+                Ok(Prepared::Done(update)) => outcomes.push((file.clone(), Ok(update))),
+                Ok(Prepared::Embed(file)) => pending.push(file),
+                Err(e) => outcomes.push((file.clone(), Err(e))),
+            }
+        }
+        let requests: Vec<_> = pending
+            .iter()
+            .map(|p| embed_documents(cache.as_ref(), embedder, &p.new_texts))
+            .collect();
+        let embeddings = join_all(requests).await;
+        for (file, embeddings) in pending.into_iter().zip(embeddings) {
+            let path = file.path.clone();
+            let update = embeddings.and_then(|(e, sent)| apply_file(file, e, sent, &mut store, &mut vector_store));
+            outcomes.push((path, update));
+        }
+        if let Some(cache) = &cache {
+            cache.flush()?;
+        }
+
+        for (file, outcome) in outcomes {
+            match outcome {
+                Ok(update) => {
+                    changed |= update.changed;
+                    report.files_indexed += 1;
+                    report.cached += update.cached;
+                    progress(IndexProgress::FileIndexed {
+                        path: file,
+                        chunks: update.chunks,
+                        embedded: update.embedded,
+                        cached: update.cached,
+                        api_calls: update.embedded.div_ceil(embedder.batch_limit().max(1)),
+                    });
+                }
+                Err(e) => {
+                    progress(IndexProgress::FileFailed { path: file.clone(), error: e.to_string() });
+                    report.failed.push(SkippedFile { path: file, reason: e.to_string() });
+                }
+            }
+        }
+    }
+
+    // Vectors an HNSW index could not remove, or enough vectors to train the
+    // configured index, call for a rebuild; unchanged chunks come from the cache
+    if !index_matches_store(&vector_store, &store, embedder, &index_config) {
+        match rebuild_index(&mut store, embedder, cache.as_ref(), &index_config, progress, cancel).await? {
+            Some(vs) => {
+                vector_store = vs;
+                changed = true;
+            }
+            None => report.cancelled = true,
+        }
+    }
+
+    // Stores from before the embedding was recorded get it on the next run
+    if changed || store.embedding().is_none() {
+        vector_store.save(&index_path)?;
+        store.set_index_description(&vector_store.kind().to_string());
+        store.set_embedding(&embedder.model(), vector_store.dimension());
+        store.save()?;
+    }
+    // Indexes built before keyword search get their keyword index on the next run
+    if changed || !vs_dir.join(KEYWORD_FILE_NAME).exists() {
+        KeywordIndex::build(&vs_dir, store.records()).save()?;
+    }
+    Ok(report)
+}
+
+/// Whether an index holds exactly the vectors described by the store, in the kind
+/// of index configured for that many vectors.
+fn index_matches_store(vector_store: &VectorStore, store: &ChunkStore, embedder: &dyn Embedder, config: &IndexConfig) -> bool {
+    let model = embedder.model();
+    *vector_store.kind() == config.kind_for(store.records().len())
+        && vector_store.len() == store.records().len()
+        && store.records().iter().all(|r| r.embedding_model == model)
+}
+
+/// Embed every chunk in the store into a new index of the configured kind, keeping
+/// the chunk ids. Indexes that need training are trained on all of the vectors.
+/// Returns `None` if cancelled part way through.
+async fn rebuild_index(
+    store: &mut ChunkStore,
+    embedder: &dyn Embedder,
+    cache: Option<&EmbeddingCache>,
+    config: &IndexConfig,
+    progress: &(dyn Fn(IndexProgress) + Send + Sync),
+    cancel: &AtomicBool,
+) -> Result<Option<VectorStore>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut vector_store = VectorStore::new(embedder.dimension(), &config.kind_for(store.records().len()))?;
+    let mut all_embeddings = Vec::new();
+    let mut all_ids = Vec::new();
+    let files = store.files();
+    let rebuilding = |files_done: usize, embedded: usize, cached: usize| IndexProgress::Rebuilding {
+        files_done,
+        files: files.len(),
+        embedded,
+        cached,
+        api_calls: embedded.div_ceil(embedder.batch_limit().max(1)),
+    };
+    progress(rebuilding(0, 0, 0));
+    for (i, file) in files.iter().enumerate() {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let texts: Vec<String> = store.file_records(file).iter().map(|r| r.contextual_text()).collect();
+        let (embeddings, sent) = embed_documents(cache, embedder, &texts).await?;
+        if let Some(cache) = cache {
+            cache.flush()?;
+        }
+        let mut records = store.remove_file(file);
+        all_ids.extend(records.iter().map(|r| r.id));
+        all_embeddings.extend(embeddings);
+        for record in &mut records {
+            record.embedding_model = embedder.model();
+        }
+        store.insert(records);
+        progress(rebuilding(i + 1, sent, texts.len() - sent));
+    }
+    vector_store.train(&all_embeddings)?;
+    vector_store.add_with_ids(&all_embeddings, &all_ids)?;
+    Ok(Some(vector_store))
+}
+
+/// Remove vectors from the index. Indexes that cannot remove vectors keep them until
+/// the end of the run, when they no longer match the store and are rebuilt.
+fn remove_vectors(vector_store: &mut VectorStore, ids: &[u64]) -> Result<(), VectorStoreError> {
+    match vector_store.remove_ids(ids) {
+        Ok(_) | Err(VectorStoreError::Unsupported { .. }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Open the embedding cache configured for the index in `vs_dir`, if caching is enabled.
+fn open_cache(vs_dir: &Path) -> Result<Option<EmbeddingCache>, Box<dyn std::error::Error + Send + Sync>> {
+    embedding_cache_dir(vs_dir).map(|dir| EmbeddingCache::open(&dir)).transpose()
+}
+
+/// Embed document chunks, through the cache when there is one. Returns the vectors
+/// and how many texts were sent to the embedder.
+async fn embed_documents(
+    cache: Option<&EmbeddingCache>,
+    embedder: &dyn Embedder,
+    texts: &[String],
+) -> Result<(Vec<Vec<f32>>, usize), Box<dyn std::error::Error + Send + Sync>> {
+    match cache {
+        Some(cache) => cache.embed_documents(embedder, texts).await,
+        None => Ok((embed_in_batches(embedder, texts).await?, texts.len())),
+    }
+}
+
+/// Size and contents of the embedding cache used for `directory`, or `None` if caching is off.
+pub fn cache_stats(directory: &Path) -> Result<Option<CacheStats>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(open_cache(&directory.join(".vs"))?.map(|cache| cache.stats()))
+}
+
+/// Remove the cached vectors that no chunk in `directory`'s index uses, returning
+/// how many were removed. A cache shared through `FISHER_EMBEDDING_CACHE` would lose
+/// the vectors only other directories use, so it is left alone unless `all_directories`.
+pub fn prune_cache(directory: &Path, all_directories: bool) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let vs_dir = directory.join(".vs");
+    let Some(cache_dir) = embedding_cache_dir(&vs_dir) else {
+        return Ok(0);
+    };
+    if cache_dir != vs_dir && !all_directories {
+        return Err(format!(
+            "the embedding cache in {} is shared through FISHER_EMBEDDING_CACHE, and pruning it for {} \
+             would drop the vectors other directories use; pass --all-directories to prune it anyway",
+            cache_dir.display(),
+            directory.display()
+        )
+        .into());
+    }
+    let cache = EmbeddingCache::open(&cache_dir)?;
+    let store = ChunkStore::open(&vs_dir)?;
+    let texts: Vec<(&str, String)> = store
+        .records()
+        .iter()
+        .map(|r| (r.embedding_model.as_str(), r.contextual_text()))
+        .collect();
+    cache.retain_documents(texts.iter().map(|(model, text)| (*model, text.as_str())))
+}
+
+/// What indexing did to one file.
+struct FileUpdate {
+    changed: bool,
+    chunks: usize,
+    /// Number of chunks sent to the embedder.
+    embedded: usize,
+    /// Number of new chunks whose vectors were found in the embedding cache.
+    cached: usize,
+}
+
+/// Where `prepare_file` left a file.
+enum Prepared {
+    /// Already up to date, with nothing to embed.
+    Done(FileUpdate),
+    /// Re-chunked, waiting for its new chunks to be embedded.
+    Embed(PendingFile),
+}
+
+/// A file's new chunk records, waiting for the vectors of the chunks with new text.
+struct PendingFile {
+    path: PathBuf,
+    records: Vec<ChunkRecord>,
+    /// Positions in `records` of the chunks that need vectors, which get their ids once embedded.
+    new_records: Vec<usize>,
+    new_texts: Vec<String>,
+    /// Ids of the old chunks that are not reused.
+    stale: Vec<u64>,
+}
+
+/// Work out what it takes to bring the vectors for one file up to date.
+///
+/// Chunks whose text is unchanged keep their ids and vectors; only new or edited
+/// chunks need to be sent to the embedding API. Apart from remembering a new mtime,
+/// the store and index are only touched by `apply_file` once embedding has
+/// succeeded, so a failure leaves the file's old state intact.
+fn prepare_file(
+    file: &Path,
+    chunking: &ChunkConfig,
+    store: &mut ChunkStore,
+    embedder: &dyn Embedder,
+) -> Result<Prepared, Box<dyn std::error::Error + Send + Sync>> {
+    let file_str = file.to_string_lossy().to_string();
+    let (file_hash, mtime) = file_fingerprint(file)?;
+    let chunking_hash = file_chunking(file, chunking)?;
+    let existing = store.file_records(&file_str);
+    // Files chunked with other settings are re-chunked even if they did not change
+    if !existing.is_empty() && existing[0].chunking == chunking_hash {
+        let chunks = existing.len();
+        if existing[0].mtime == mtime {
+            return Ok(Prepared::Done(FileUpdate { changed: false, chunks, embedded: 0, cached: 0 }));
+        }
+        if existing[0].file_hash == file_hash {
+            // Touched but not edited: just remember the new mtime
+            let mut records = store.remove_file(&file_str);
+            for record in &mut records {
+                record.mtime = mtime;
             }
+            store.insert(records);
+            return Ok(Prepared::Done(FileUpdate { changed: true, chunks, embedded: 0, cached: 0 }));
         }
     }
 
-    // Assume embedding dimension is 768 for now
-    let embedding_dim = 3072;
-    let mut vector_store = VectorStore::new(embedding_dim).expect("Failed to create VectorStore");
+    // Pool the old vectors by embedded text so unchanged chunks can be reused
+    let mut reusable: HashMap<String, Vec<u64>> = HashMap::new();
+    for record in existing {
+        reusable.entry(record.contextual_text()).or_default().push(record.id);
+    }
 
-    if is_empty {
-        // Create new vector store and add all files
-        for file in &files {
-            let file_str = file.to_str().unwrap().to_string();
-            let chunks = process_file(file.clone());
-            if !chunks.is_empty() {
-                let embeddings = generate_embedding_document(&chunks).await.expect("Failed to embed");
-                vector_store.add(&embeddings).expect("Failed to add to vector store");
-                add_to_faiss_lookup(directory.clone(), chunks.len(), file_str);
+    let chunks = process_file(file, chunking)?;
+    let mut pending = PendingFile {
+        path: file.to_path_buf(),
+        records: Vec::new(),
+        new_records: Vec::new(),
+        new_texts: Vec::new(),
+        stale: Vec::new(),
+    };
+    for (i, c) in chunks.into_iter().enumerate() {
+        let mut record = ChunkRecord {
+            id: 0,
+            file: file_str.clone(),
+            chunk: i,
+            text: c.text,
+            start: c.start,
+            end: c.end,
+            heading: c.heading,
+            symbol: c.symbol,
+            lines: c.lines,
+            pages: c.pages,
+            records: c.records,
+            title: c.title,
+            author: c.author,
+            file_hash: file_hash.clone(),
+            chunking: chunking_hash.clone(),
+            mtime,
+            embedding_model: embedder.model(),
+        };
+        let text = record.contextual_text();
+        match reusable.get_mut(&text).and_then(|ids| ids.pop()) {
+            Some(id) => record.id = id,
+            None => {
+                pending.new_records.push(i);
+                pending.new_texts.push(text);
             }
         }
+        pending.records.push(record);
+    }
+    pending.stale = reusable.into_values().flatten().collect();
+    Ok(Prepared::Embed(pending))
+}
+
+/// Replace a file's chunks and vectors with those of `file`, given the vectors of
+/// its new chunks, `sent` of which came from the embedder rather than the cache.
+fn apply_file(
+    file: PendingFile,
+    embeddings: Vec<Vec<f32>>,
+    sent: usize,
+    store: &mut ChunkStore,
+    vector_store: &mut VectorStore,
+) -> Result<FileUpdate, Box<dyn std::error::Error + Send + Sync>> {
+    let PendingFile { path, mut records, new_records, new_texts, stale } = file;
+    let first_id = store.next_id();
+    let new_ids: Vec<u64> = (first_id..).take(new_records.len()).collect();
+    for (&i, &id) in new_records.iter().zip(&new_ids) {
+        records[i].id = id;
+    }
+
+    let update = FileUpdate {
+        changed: !records.is_empty() || !stale.is_empty(),
+        chunks: records.len(),
+        embedded: sent,
+        cached: new_texts.len() - sent,
+    };
+    // Add the new vectors first: a mismatched embedding fails here, before anything is removed
+    vector_store.add_with_ids(&embeddings, &new_ids)?;
+    if let Err(e) = remove_vectors(vector_store, &stale) {
+        let _ = vector_store.remove_ids(&new_ids);
+        return Err(e.into());
+    }
+    store.remove_file(&path.to_string_lossy());
+    store.insert(records);
+    Ok(update)
+}
+
+/// State of a directory's index compared to the files on disk.
+#[derive(Debug, Serialize)]
+pub struct IndexStatus {
+    /// Chunks in the metadata store.
+    pub chunks: usize,
+    /// Vectors in the saved index, or `None` if there is no index yet.
+    pub vectors: Option<usize>,
+    pub dimension: Option<usize>,
+    pub index_description: String,
+    /// Embedding models the stored chunks were embedded with.
+    pub embedding_models: Vec<String>,
+    pub files_indexed: usize,
+    /// Indexed files whose contents or chunking settings changed since they were indexed.
+    pub stale: Vec<PathBuf>,
+    /// Files that would be indexed but are not yet.
+    pub new: Vec<PathBuf>,
+    /// Indexed files that no longer exist or are now excluded.
+    pub deleted: Vec<String>,
+}
+
+/// Compare the index for `directory` with the files on disk, without changing either.
+pub fn index_status(directory: &Path) -> Result<IndexStatus, Box<dyn std::error::Error + Send + Sync>> {
+    let vs_dir = directory.join(".vs");
+    let store = ChunkStore::open(&vs_dir)?;
+    let index_path = vs_dir.join(INDEX_FILE_NAME);
+    let index = if index_path.exists() {
+        Some(VectorStore::open(&index_path, &IndexKind::from_description(store.index_description()))?)
     } else {
-        // Only add new files
-        for file in &files {
-            let file_str = file.to_str().unwrap().to_string();
-            if !existing_files.contains(&file_str) {
-                let chunks = process_file(file.clone());
-                if !chunks.is_empty() {
-                    let embeddings = generate_embedding_document(&chunks).await.expect("Failed to embed");
-                    vector_store.add(&embeddings).expect("Failed to add to vector store");
-                    add_to_faiss_lookup(directory.clone(), chunks.len(), file_str);
-                }
+        None
+    };
+
+    let mut embedding_models: Vec<String> = Vec::new();
+    for record in store.records() {
+        if !embedding_models.contains(&record.embedding_model) {
+            embedding_models.push(record.embedding_model.clone());
+        }
+    }
+
+    let scan = get_files(directory, &WalkOptions::from_env())?;
+    let chunking = ChunkConfig::from_env();
+    let mut stale = Vec::new();
+    let mut new = Vec::new();
+    for file in &scan.files {
+        let records = store.file_records(&file.to_string_lossy());
+        let Some(first) = records.first() else {
+            new.push(file.clone());
+            continue;
+        };
+        let (file_hash, mtime) = file_fingerprint(file)?;
+        if (first.mtime != mtime && first.file_hash != file_hash) || first.chunking != file_chunking(file, &chunking)? {
+            stale.push(file.clone());
+        }
+    }
+    let file_names: HashSet<String> = scan.files.iter().map(|f| f.to_string_lossy().to_string()).collect();
+    let indexed = store.files();
+    let deleted = indexed.iter().filter(|f| !file_names.contains(*f)).cloned().collect();
+
+    Ok(IndexStatus {
+        chunks: store.records().len(),
+        vectors: index.as_ref().map(|vs| vs.len()),
+        dimension: index.as_ref().map(|vs| vs.dimension()),
+        index_description: store.index_description().to_string(),
+        embedding_models,
+        files_indexed: indexed.len(),
+        stale,
+        new,
+        deleted,
+    })
+}
+
+/// Length of the vectors in `directory`'s index, if it was built with `model`. Lets
+/// embedders skip probing the server for their dimension.
+pub fn indexed_dimension(directory: &Path, model: &str) -> Option<usize> {
+    read_embedding(&directory.join(".vs"))
+        .filter(|(indexed_model, _)| indexed_model == model)
+        .map(|(_, dimension)| dimension)
+}
+
+/// Load the persisted vector store for `directory`, of the kind recorded in its
+/// chunk store, with the configured search parameters.
+pub fn load_vector_store(directory: &Path, store: &ChunkStore, dim: usize) -> Result<VectorStore, Box<dyn std::error::Error + Send + Sync>> {
+    let index_path = directory.join(".vs").join(INDEX_FILE_NAME);
+    let mut vector_store = VectorStore::load(&index_path, dim, &IndexKind::from_description(store.index_description()))?;
+    let config = IndexConfig::from_env();
+    vector_store.set_search_parameters(config.nprobe, config.ef_search)?;
+    Ok(vector_store)
+}
+
+/// SHA-256 of a file's contents and its modification time in seconds since the Unix epoch.
+pub fn file_fingerprint(file: &Path) -> std::io::Result<(String, u64)> {
+    let hash = format!("{:x}", Sha256::digest(read(file)?));
+    let mtime = std::fs::metadata(file)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((hash, mtime))
+}
+
+/// Fingerprint of the chunking settings that apply to `file`, see `chunking_fingerprint`.
+fn file_chunking(file: &Path, chunking: &ChunkConfig) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    File::open(file)?.take(HEAD_LEN as u64).read_to_end(&mut head)?;
+    Ok(chunking_fingerprint(file, registry().loader_for(file, &head), chunking))
+}
+
+/// Rebuild chunk records from an old `faiss_lookup.txt`, re-chunking each file to recover its text.
+///
+/// If a file has changed since it was indexed its vectors can no longer be matched
+/// to chunks, so the store is left empty and the directory is re-indexed from scratch.
+fn migrate_legacy_lookup(vs_dir: &Path, store: &mut ChunkStore) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(entries) = read_legacy_lookup(vs_dir) else {
+        return Ok(());
+    };
+
+    let mut records = Vec::new();
+    for (file_name, chunk_count) in entries {
+        let file = PathBuf::from(&file_name);
+        let chunks = match legacy_text(&file) {
+            Ok(text) if file.is_file() => legacy_chunks(&text),
+            _ => Vec::new(),
+        };
+        if chunks.len() != chunk_count {
+            records.clear();
+            break;
+        }
+        let (file_hash, mtime) = file_fingerprint(&file)?;
+        for (i, c) in chunks.into_iter().enumerate() {
+            records.push(ChunkRecord {
+                id: records.len() as u64,
+                file: file_name.clone(),
+                chunk: i,
+                text: c.text,
+                start: c.start,
+                end: c.end,
+                heading: None,
+                symbol: None,
+                lines: None,
+                pages: None,
+                records: None,
+                title: None,
+                author: None,
+                file_hash: file_hash.clone(),
+                // Left empty so the file is re-chunked with the current chunkers
+                chunking: String::new(),
+                mtime,
+                // faiss_lookup.txt predates configurable embedders
+                embedding_model: GeminiEmbedder::MODEL.to_string(),
+            });
+        }
+    }
+
+    store.insert(records);
+    store.save()?;
+    retire_legacy_lookup(vs_dir)?;
+    Ok(())
+}
+
+/// Where a retrieved chunk came from, shown as a footnote under an answer.
+#[derive(Debug, Clone, Serialize)]
+pub struct Source {
+    /// Id of the chunk's vector in the index.
+    pub id: u64,
+    pub file: String,
+    pub chunk: usize,
+    pub range: Option<(usize, usize)>,
+    /// Heading breadcrumb of the section the chunk is in.
+    pub heading: Option<String>,
+    /// Function, type or other item the chunk is part of.
+    pub symbol: Option<String>,
+    /// 1-based first and last line of the chunk.
+    pub lines: Option<(usize, usize)>,
+    /// 1-based first and last page of the chunk, for paged documents.
+    pub pages: Option<(usize, usize)>,
+    /// 1-based indices of the first and last row or record in the chunk, for structured data.
+    pub records: Option<(usize, usize)>,
+    /// Similarity of the chunk to the query, when it was found by vector search.
+    pub score: Option<f32>,
+    /// BM25 score of the chunk, when it was found by keyword search.
+    pub bm25: Option<f32>,
+}
+
+impl Source {
+    /// The file, with the chunk's line, page or record range when known, as in
+    /// `src/main.rs:10-42`, `report.pdf p. 3-4` or `sales.csv records 7-9`.
+    pub fn location(&self) -> String {
+        if let Some((first, last)) = self.records {
+            return if first == last {
+                format!("{} record {}", self.file, first)
+            } else {
+                format!("{} records {}-{}", self.file, first, last)
+            };
+        }
+        match (self.lines, self.pages) {
+            (Some((first, last)), _) if first == last => format!("{}:{}", self.file, first),
+            (Some((first, last)), _) => format!("{}:{}-{}", self.file, first, last),
+            (None, Some((first, last))) if first == last => format!("{} p. {}", self.file, first),
+            (None, Some((first, last))) => format!("{} p. {}-{}", self.file, first, last),
+            (None, None) => self.file.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (", self.location())?;
+        if let Some(label) = self.heading.as_ref().or(self.symbol.as_ref()) {
+            write!(f, "{}, ", label)?;
+        }
+        match (self.lines.or(self.pages).or(self.records), self.range) {
+            (None, Some((start, end))) => write!(f, "chunk {}, bytes {}-{})", self.chunk, start, end)?,
+            _ => write!(f, "chunk {})", self.chunk)?,
+        }
+        if let Some(score) = self.score {
+            write!(f, " score {:.2}", score)?;
+        }
+        if let Some(bm25) = self.bm25 {
+            write!(f, " bm25 {:.2}", bm25)?;
+        }
+        Ok(())
+    }
+}
+
+/// A piece of a file's text together with its byte range in that text.
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+    /// Breadcrumb of the headings above the chunk, such as `Guide > Install > Linux`.
+    pub heading: Option<String>,
+    /// Name of the function, type or other item the chunk is part of.
+    pub symbol: Option<String>,
+    /// 1-based first and last line of the chunk in its file.
+    pub lines: Option<(usize, usize)>,
+    /// 1-based first and last page of the chunk, for paged documents.
+    pub pages: Option<(usize, usize)>,
+    /// 1-based indices of the first and last row or record in the chunk, for structured data.
+    pub records: Option<(usize, usize)>,
+    /// Title of the document, from its own metadata.
+    pub title: Option<String>,
+    /// Author of the document, from its own metadata.
+    pub author: Option<String>,
+}
+
+/// A file left out of indexing and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Files found under a directory, plus those that were skipped.
+#[derive(Debug, Default)]
+pub struct FileScan {
+    pub files: Vec<PathBuf>,
+    pub skipped: Vec<SkippedFile>,
+}
+
+/// Recursively collect the files to index under `directory`.
+///
+/// Hidden files, `.vs/` and anything matched by `.gitignore` or `.fisherignore`
+/// are left out silently; files rejected by size, symlink policy or binary
+/// detection, and entries that could not be read, are reported in `skipped`.
+pub fn get_files(directory: &Path, options: &WalkOptions) -> Result<FileScan, Box<dyn std::error::Error + Send + Sync>> {
+    let mut overrides = OverrideBuilder::new(directory);
+    for glob in &options.include {
+        overrides.add(glob)?;
+    }
+    for glob in &options.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+
+    let walker = WalkBuilder::new(directory)
+        .hidden(true)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .follow_links(options.follow_symlinks)
+        .overrides(overrides.build()?)
+        .build();
+
+    let mut scan = FileScan::default();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = match &e {
+                    ignore::Error::WithPath { path, .. } => path.clone(),
+                    _ => directory.to_path_buf(),
+                };
+                scan.skipped.push(SkippedFile { path, reason: e.to_string() });
+                continue;
             }
+        };
+        let path = entry.path().to_path_buf();
+        let Some(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            continue;
+        }
+        if file_type.is_symlink() {
+            scan.skipped.push(SkippedFile { path, reason: "symbolic link".to_string() });
+            continue;
+        }
+        match check_file(&path, options.max_file_size) {
+            Ok(()) => scan.files.push(path),
+            Err(reason) => scan.skipped.push(SkippedFile { path, reason }),
         }
     }
+    scan.files.sort();
+    Ok(scan)
 }
 
-pub fn add_to_faiss_lookup(directory: PathBuf, num_chunks: usize, file_name: String) {
-    let faiss_lookup = directory.join(".vs").join("faiss_lookup.txt");
-    let mut faiss_lookup = OpenOptions::new().append(true).open(&faiss_lookup).unwrap();
-    faiss_lookup.write_all(file_name.as_bytes()).unwrap();
-    faiss_lookup.write_all(b" ").unwrap();
-    faiss_lookup.write_all(num_chunks.to_string().as_bytes()).unwrap();
-    faiss_lookup.write_all(b"\n").unwrap();
+/// Reject files that are too large or look binary without a loader for their format.
+fn check_file(path: &Path, max_file_size: u64) -> Result<(), String> {
+    let size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+    if size > max_file_size {
+        return Err(format!("larger than {} bytes", max_file_size));
+    }
+
+    let mut head = [0u8; HEAD_LEN];
+    let n = File::open(path)
+        .and_then(|mut f| f.read(&mut head))
+        .map_err(|e| e.to_string())?;
+    if registry().loader_for(path, &head[..n]).binary() {
+        return Ok(());
+    }
+
+    if looks_binary(&head[..n]) {
+        return Err("binary file".to_string());
+    }
+    Ok(())
 }
 
-pub fn get_files(directory: PathBuf) -> Vec<PathBuf> {
-    let mut files = Vec::new();
-    for entry in std::fs::read_dir(directory).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() {
-            files.push(path);
+/// Read `file` and split it with the chunker configured for its type.
+pub fn process_file(file: &Path, config: &ChunkConfig) -> Result<Vec<Chunk>, Box<dyn std::error::Error + Send + Sync>> {
+    let document = registry().load(file)?;
+    let mut chunks = chunker_for(file, config).chunk(&document.text);
+
+    // Paged documents are cited by page and structured data by record; line numbers
+    // of extracted text point nowhere useful
+    let newlines: Vec<usize> = document.text.match_indices('\n').map(|(i, _)| i).collect();
+    for chunk in &mut chunks {
+        if let Some(pages) = document.page_range(chunk.start, chunk.end) {
+            chunk.pages = Some(pages);
+        } else if let Some(records) = document.record_range(chunk.start, chunk.end) {
+            chunk.records = Some(records);
+        } else if !document.extracted {
+            chunk.lines = Some(line_range(&newlines, chunk.start, chunk.end));
         }
+        chunk.title = document.title.clone();
+        chunk.author = document.author.clone();
     }
-    files
+    Ok(chunks)
 }
 
-pub fn process_file(file: PathBuf) -> Vec<String> {
+/// Read the text of `file` the way indexes built before page-aware extraction did.
+fn legacy_text(file: &Path) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     if file.extension().unwrap_or_default() == "pdf" {
-        let chunks = prepare_pdf(&file);
-        println!("Chunks: {:?}", chunks);
-        chunks
+        let bytes = read(file)?;
+        let text = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem(&bytes))
+            .map_err(|_| "PDF could not be parsed")??;
+        Ok(text)
     } else {
-        let text = read_to_string(file).expect("Failed to read file");
-        let chunks = chunk_text(&text);
-        println!("Chunks: {:?}", chunks);
-        chunks
+        Ok(read_to_string(file)?)
     }
 }
 
-pub fn chunk_text(text: &str) -> Vec<String> {
+/// Split text the way indexes built before configurable chunking did, so their
+/// vectors can still be matched to chunks when migrating `faiss_lookup.txt`.
+fn legacy_chunks(text: &str) -> Vec<Chunk> {
     let mut chunks = Vec::new();
     let mut current_chunk = String::new();
     let mut current_length = 0;
+    let mut start = 0;
+    let mut end = 0;
     let max_length = 1000;
 
     for line in text.lines() {
+        let offset = line.as_ptr() as usize - text.as_ptr() as usize;
         if current_length + line.len() > max_length {
-            chunks.push(current_chunk);
+            chunks.push(Chunk { text: current_chunk, start, end, ..Chunk::default() });
             current_chunk = String::new();
             current_length = 0;
         }
+        if current_chunk.is_empty() {
+            start = offset;
+        }
         current_chunk.push_str(line);
         current_length += line.len();
+        end = offset + line.len();
     }
 
     if !current_chunk.is_empty() {
-        chunks.push(current_chunk);
+        chunks.push(Chunk { text: current_chunk, start, end, ..Chunk::default() });
     }
 
     chunks
 }
 
-pub fn prepare_pdf(pdf_path: &PathBuf) -> Vec<String> {
-    let bytes = read(pdf_path).unwrap();
-    let text = pdf_extract::extract_text_from_mem(&bytes).unwrap();
-    chunk_text(&text)
-}   
-
-pub fn get_chunk(directory: PathBuf, vector_index: usize) -> Option<String> {
-    // Open the faiss_lookup.txt file
-    let faiss_lookup_path = directory.join(".vs").join("faiss_lookup.txt");
-    let file = File::open(faiss_lookup_path).ok()?;
-    let reader = BufReader::new(file);
-
-    // Build a list of (filename, chunk_count)
-    let mut file_chunks: Vec<(String, usize)> = Vec::new();
-    for line in reader.lines() {
-        let line = line.unwrap();
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() == 2 {
-            let filename = parts[0].to_string();
-            if let Ok(chunk_count) = parts[1].parse::<usize>() {
-                file_chunks.push((filename, chunk_count));
-            }
-        }
+/// A chunk found by a search, with how well it matched the query.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    /// Score the hits of a search are ranked by: the similarity for vector search
+    /// alone, the fused reciprocal rank for hybrid search.
+    pub score: f32,
+    /// Similarity to the query, when found by vector search.
+    pub similarity: Option<f32>,
+    /// BM25 score, when found by keyword search.
+    pub bm25: Option<f32>,
+    pub record: ChunkRecord,
+}
+
+impl SearchHit {
+    /// The chunk's text, led by its location so the model can cite it, and its source.
+    pub fn context(&self) -> (String, Source) {
+        let record = &self.record;
+        let source = Source {
+            id: record.id,
+            file: record.file.clone(),
+            chunk: record.chunk,
+            range: Some((record.start, record.end)),
+            heading: record.heading.clone(),
+            symbol: record.symbol.clone(),
+            lines: record.lines,
+            pages: record.pages,
+            records: record.records,
+            score: self.similarity,
+            bm25: self.bm25,
+        };
+        (format!("{}\n{}", source.location(), record.contextual_text()), source)
     }
+}
 
-    // Find which file and chunk this index corresponds to
-    let mut idx = vector_index;
-    for (filename, chunk_count) in file_chunks {
-        if idx < chunk_count {
-            // This is the file and chunk we want
-            let file_path = PathBuf::from(&filename);
-            let chunks = process_file(file_path);
-            if idx < chunks.len() {
-                return Some(chunks[idx].clone());
-            } else {
-                return None;
+/// Build the globs of `filter`, which are relative to `directory`.
+fn search_globs(directory: &Path, filter: &SearchFilter) -> Result<Override, Box<dyn std::error::Error + Send + Sync>> {
+    let mut globs = OverrideBuilder::new(directory);
+    for glob in &filter.globs {
+        globs.add(glob)?;
+    }
+    Ok(globs.build()?)
+}
+
+/// Whether the chunk in `record` passes the file conditions of `filter`, with its
+/// globs already built into `globs`.
+fn filter_matches(filter: &SearchFilter, globs: &Override, record: &ChunkRecord) -> bool {
+    let path = Path::new(&record.file);
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    (filter.globs.is_empty() || globs.matched(path, false).is_whitelist())
+        && (filter.file_types.is_empty()
+            || filter.file_types.iter().any(|t| t.trim_start_matches('.').eq_ignore_ascii_case(&extension)))
+        && filter.modified_after.is_none_or(|after| record.mtime >= after)
+        && filter.modified_before.is_none_or(|before| record.mtime < before)
+}
+
+/// The chunk store, vector index and keyword index of an indexed directory, loaded
+/// once and then searched for every question.
+pub struct SearchIndex {
+    directory: PathBuf,
+    store: ChunkStore,
+    /// FAISS searches need exclusive access to the index.
+    vectors: Mutex<VectorStore>,
+    keywords: KeywordIndex,
+}
+
+impl SearchIndex {
+    /// Load the indexes saved for `directory`, whose vectors have dimension `dim`.
+    pub fn open(directory: &Path, dim: usize) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let vs_dir = directory.join(".vs");
+        let store = ChunkStore::open(&vs_dir)?;
+        let vectors = load_vector_store(directory, &store, dim)?;
+        let keywords = KeywordIndex::open(&vs_dir, &store)?;
+        Ok(SearchIndex { directory: directory.to_path_buf(), store, vectors: Mutex::new(vectors), keywords })
+    }
+}
+
+/// Search for the `k` chunks nearest to `query` that pass `filter`, most similar first.
+///
+/// Vectors whose ids have no chunk in the store are skipped. When the filter
+/// rejects files, the search widens until `k` chunks pass, the score cutoff is
+/// reached or the whole index has been searched.
+pub async fn query_vector_store(
+    query: &str,
+    index: &SearchIndex,
+    embedder: &dyn Embedder,
+    k: usize,
+    filter: &SearchFilter,
+) -> Result<Vec<SearchHit>, Box<dyn std::error::Error + Send + Sync>> {
+    let globs = search_globs(&index.directory, filter)?;
+
+    // Generate embedding for the query string
+    let embedding = embedder.embed_query(query).await?;
+    let mut vector_store = index.vectors.lock().unwrap_or_else(|e| e.into_inner());
+    let metric = vector_store.kind().metric;
+    let total = vector_store.len();
+    let mut fetch = if filter.filters_files() { k.saturating_mul(4) } else { k };
+    loop {
+        let mut hits = Vec::new();
+        let mut below_cutoff = false;
+        for (id, distance) in vector_store.query(&embedding, fetch.min(total))? {
+            let score = metric.similarity(distance);
+            if filter.min_score.is_some_and(|min| score < min) {
+                below_cutoff = true;
+                break;
             }
-        } else {
-            idx -= chunk_count;
+            let Some(record) = index.store.get(id) else {
+                continue;
+            };
+            if filter_matches(filter, &globs, record) {
+                hits.push(SearchHit { score, similarity: Some(score), bm25: None, record: record.clone() });
+            }
+            if hits.len() == k {
+                break;
+            }
+        }
+        if hits.len() >= k || below_cutoff || fetch >= total {
+            return Ok(hits);
         }
+        fetch = fetch.saturating_mul(4);
     }
-    eprintln!("Vector index out of range"); 
-    None
 }
 
-/// Query the vector store with a string and return the indices of the nearest neighbors.
-pub async fn query_vector_store(query: &str, directory: PathBuf) -> Result<Vec<usize>, Box<dyn std::error::Error + Send + Sync>> {
-    use crate::faiss::VectorStore;
-    use crate::model::generate_embedding_query;
-    // Assume embedding dimension is 3072
-    let embedding_dim = 3072;
-    let mut vector_store = VectorStore::new(embedding_dim)?;
+/// Search for the `k` chunks most relevant to `query` that pass `filter`, fusing the
+/// vector and BM25 keyword rankings by reciprocal rank. `keyword_weight`, between 0
+/// and 1, is the share of the fused score given to the keyword ranking; at 0 only
+/// vector search is run and at 1 only keyword search.
+///
+/// The similarity cutoff of `filter` applies to vector matches; chunks found by
+/// keyword alone are kept.
+pub async fn hybrid_search(
+    query: &str,
+    index: &SearchIndex,
+    embedder: &dyn Embedder,
+    k: usize,
+    filter: &SearchFilter,
+    keyword_weight: f32,
+) -> Result<Vec<SearchHit>, Box<dyn std::error::Error + Send + Sync>> {
+    if keyword_weight <= 0.0 {
+        return query_vector_store(query, index, embedder, k, filter).await;
+    }
+    // Fuse deeper rankings than asked for, so chunks ranked fairly well by both can rise
+    let depth = k.saturating_mul(4);
+    let vector_hits = if keyword_weight < 1.0 {
+        query_vector_store(query, index, embedder, depth, filter).await?
+    } else {
+        Vec::new()
+    };
+    let globs = search_globs(&index.directory, filter)?;
+    let keyword_hits: Vec<(&ChunkRecord, f32)> = index
+        .keywords
+        .search(query)
+        .into_iter()
+        .filter_map(|(id, bm25)| Some((index.store.get(id)?, bm25)))
+        .filter(|(record, _)| filter_matches(filter, &globs, record))
+        .take(depth)
+        .collect();
 
-    // TODO: Load vectors from disk or reconstruct from files/faiss_lookup if persistence is needed
-    // For now, this is a fresh index and will return nothing meaningful unless populated in this session
+    let mut fused: HashMap<u64, SearchHit> = HashMap::new();
+    for (rank, hit) in vector_hits.into_iter().enumerate() {
+        let score = (1.0 - keyword_weight) / (RRF_K + rank as f32 + 1.0);
+        fused.insert(hit.record.id, SearchHit { score, ..hit });
+    }
+    for (rank, (record, bm25)) in keyword_hits.into_iter().enumerate() {
+        let hit = fused.entry(record.id).or_insert_with(|| SearchHit {
+            score: 0.0,
+            similarity: None,
+            bm25: None,
+            record: record.clone(),
+        });
+        hit.score += keyword_weight / (RRF_K + rank as f32 + 1.0);
+        hit.bm25 = Some(bm25);
+    }
+    let mut hits: Vec<SearchHit> = fused.into_values().collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.record.id.cmp(&b.record.id)));
+    hits.truncate(k);
+    Ok(hits)
+}
 
-    // Generate embedding for the query string
-    let embedding = generate_embedding_query(query).await?;
-    // Query the vector store for the top 5 nearest neighbors
-    let k = 5;
-    let (_distances, indices) = vector_store.query(&embedding, k)?;
-    Ok(indices.into_iter().map(|i| i.get().unwrap() as usize).collect())
+/// Retrieve the text and source of the `k` chunks most relevant to `query` that
+/// pass `filter`, most relevant first, weighting keyword matches by `FISHER_KEYWORD_WEIGHT`.
+pub async fn retrieve_context(
+    query: &str,
+    index: &SearchIndex,
+    embedder: &dyn Embedder,
+    k: usize,
+    filter: &SearchFilter,
+) -> Result<Vec<(String, Source)>, Box<dyn std::error::Error + Send + Sync>> {
+    let hits = hybrid_search(query, index, embedder, k, filter, keyword_weight()).await?;
+    Ok(hits.iter().map(SearchHit::context).collect())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(file: &str, mtime: u64) -> ChunkRecord {
+        serde_json::from_value(serde_json::json!({
+            "id": 0, "file": file, "chunk": 0, "text": "text", "start": 0, "end": 4,
+            "file_hash": "", "mtime": mtime, "embedding_model": "hash-8",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_apply_leaves_the_file_as_it_was() {
+        let vs_dir = std::env::temp_dir().join(format!("fisher-apply-{}", std::process::id()));
+        let mut store = ChunkStore::open(&vs_dir).unwrap();
+        let old: Vec<ChunkRecord> = (0..2)
+            .map(|id| ChunkRecord { id, chunk: id as usize, ..record("docs/a.txt", 1) })
+            .collect();
+        store.insert(old);
+        let mut vector_store = VectorStore::new(4, &IndexConfig::default().kind_for(0)).unwrap();
+        vector_store.add_with_ids(&[vec![1.0; 4], vec![0.5; 4]], &[0, 1]).unwrap();
+
+        // The first chunk is kept, the second replaced by one whose vector has the wrong length
+        let file = PendingFile {
+            path: PathBuf::from("docs/a.txt"),
+            records: vec![
+                ChunkRecord { id: 0, ..record("docs/a.txt", 2) },
+                ChunkRecord { chunk: 1, text: "new".to_string(), ..record("docs/a.txt", 2) },
+            ],
+            new_records: vec![1],
+            new_texts: vec!["new".to_string()],
+            stale: vec![1],
+        };
+        assert!(apply_file(file, vec![vec![1.0; 3]], 1, &mut store, &mut vector_store).is_err());
+
+        let records = store.file_records("docs/a.txt");
+        assert_eq!(records.iter().map(|r| (r.id, r.mtime)).collect::<Vec<_>>(), [(0, 1), (1, 1)]);
+        assert_eq!(store.next_id(), 2);
+        assert_eq!(vector_store.len(), 2);
+    }
+
+    /// Embeds every query as the same vector.
+    struct FixedEmbedder(Vec<f32>);
+
+    #[async_trait::async_trait]
+    impl Embedder for FixedEmbedder {
+        fn model(&self) -> String {
+            "fixed".to_string()
+        }
+
+        fn dimension(&self) -> usize {
+            self.0.len()
+        }
+
+        fn batch_limit(&self) -> usize {
+            usize::MAX
+        }
+
+        async fn embed_documents(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(vec![self.0.clone(); texts.len()])
+        }
+
+        async fn embed_query(&self, _query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// An index of four chunks: by similarity to `[1, 0, 0, 0]` they rank 1, 2, 3, 4,
+    /// and all but the first contain "apple".
+    fn fusion_index() -> SearchIndex {
+        let chunks = [
+            (1, "zebra", [1.0, 0.0, 0.0, 0.0]),
+            (2, "apple banana", [0.9, 0.1, 0.0, 0.0]),
+            (3, "apple", [0.0, 1.0, 0.0, 0.0]),
+            (4, "apple apple cherry", [-1.0, 0.0, 0.0, 0.0]),
+        ];
+        let vs_dir = Path::new("docs/.vs");
+        let mut store = ChunkStore::open(vs_dir).unwrap();
+        store.insert(
+            chunks
+                .iter()
+                .map(|&(id, text, _)| ChunkRecord { id, text: text.to_string(), ..record("docs/a.txt", 0) })
+                .collect(),
+        );
+        let mut vectors = VectorStore::new(4, &IndexConfig::default().kind_for(0)).unwrap();
+        let (ids, embeddings): (Vec<u64>, Vec<Vec<f32>>) = chunks.iter().map(|&(id, _, v)| (id, v.to_vec())).unzip();
+        vectors.add_with_ids(&embeddings, &ids).unwrap();
+        let keywords = KeywordIndex::build(vs_dir, store.records());
+        SearchIndex { directory: PathBuf::from("docs"), store, vectors: Mutex::new(vectors), keywords }
+    }
+
+    fn hit_ids(hits: &[SearchHit]) -> Vec<u64> {
+        hits.iter().map(|h| h.record.id).collect()
+    }
+
+    #[tokio::test]
+    async fn weight_zero_is_the_vector_ranking() {
+        let index = fusion_index();
+        let embedder = FixedEmbedder(vec![1.0, 0.0, 0.0, 0.0]);
+        let filter = SearchFilter::default();
+        let hits = hybrid_search("apple", &index, &embedder, 4, &filter, 0.0).await.unwrap();
+        let vector_hits = query_vector_store("apple", &index, &embedder, 4, &filter).await.unwrap();
+        assert_eq!(hit_ids(&hits), [1, 2, 3, 4]);
+        assert_eq!(hit_ids(&hits), hit_ids(&vector_hits));
+        assert!(hits.iter().all(|h| h.bm25.is_none() && h.similarity == Some(h.score)));
+    }
+
+    #[tokio::test]
+    async fn weight_one_is_the_keyword_ranking() {
+        let index = fusion_index();
+        let embedder = FixedEmbedder(vec![1.0, 0.0, 0.0, 0.0]);
+        let hits = hybrid_search("apple", &index, &embedder, 4, &SearchFilter::default(), 1.0).await.unwrap();
+        let keyword_ranking: Vec<u64> = index.keywords.search("apple").into_iter().map(|(id, _)| id).collect();
+        assert_eq!(hit_ids(&hits), keyword_ranking);
+        assert!(!hit_ids(&hits).contains(&1));
+        assert!(hits.iter().all(|h| h.similarity.is_none() && h.bm25.is_some()));
+    }
+
+    #[tokio::test]
+    async fn even_weight_ranks_chunks_found_by_both_first() {
+        let index = fusion_index();
+        let embedder = FixedEmbedder(vec![1.0, 0.0, 0.0, 0.0]);
+        // The cutoff leaves chunks 1 and 2 to the vector search, and "apple" finds 2, 3 and 4
+        let filter = SearchFilter { min_score: Some(0.5), ..SearchFilter::default() };
+        let hits = hybrid_search("apple", &index, &embedder, 4, &filter, 0.5).await.unwrap();
+        assert_eq!(hits.len(), 4);
+        assert_eq!(hits[0].record.id, 2);
+        assert!(hits[0].similarity.is_some() && hits[0].bm25.is_some());
+        assert!(hits[1..].iter().all(|h| h.similarity.is_none() != h.bm25.is_none()));
+        assert!(hits[0].score > hits[1].score);
+    }
+
+    #[test]
+    fn legacy_lookup_migrates_into_the_store() {
+        let dir = std::env::temp_dir().join(format!("fisher-migrate-{}", std::process::id()));
+        let vs_dir = dir.join(".vs");
+        fs::create_dir_all(&vs_dir).unwrap();
+        let file = dir.join("release notes.txt");
+        let text = format!("{}\n", "x".repeat(99)).repeat(15);
+        fs::write(&file, &text).unwrap();
+        let chunks = legacy_chunks(&text);
+        assert_eq!(chunks.len(), 2);
+        let ranges: Vec<String> = chunks.iter().map(|c| format!("{}-{}", c.start, c.end)).collect();
+        let lookup = format!("{} {} {}\n", file.display(), chunks.len(), ranges.join(","));
+        fs::write(vs_dir.join("faiss_lookup.txt"), lookup).unwrap();
+
+        let mut store = ChunkStore::open(&vs_dir).unwrap();
+        migrate_legacy_lookup(&vs_dir, &mut store).unwrap();
+        let reopened = ChunkStore::open(&vs_dir).unwrap();
+        let migrated_aside = vs_dir.join("faiss_lookup.txt.migrated").is_file();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(migrated_aside);
+        assert_eq!(reopened.records().len(), 2);
+        for (id, chunk) in chunks.iter().enumerate() {
+            let record = reopened.get(id as u64).unwrap();
+            assert_eq!(record.file, file.to_string_lossy());
+            assert_eq!((record.chunk, record.start, record.end), (id, chunk.start, chunk.end));
+            assert_eq!(record.text, chunk.text);
+            assert_eq!(record.embedding_model, GeminiEmbedder::MODEL);
+            assert!(record.chunking.is_empty());
+        }
+    }
+
+    #[test]
+    fn date_bounds_include_after_and_exclude_before() {
+        let directory = Path::new("docs");
+        let filter = SearchFilter { modified_after: Some(1_000), modified_before: Some(2_000), ..SearchFilter::default() };
+        let globs = search_globs(directory, &filter).unwrap();
+        let matches = |mtime| filter_matches(&filter, &globs, &record("docs/a.txt", mtime));
+        assert!(!matches(999));
+        assert!(matches(1_000));
+        assert!(matches(1_999));
+        assert!(!matches(2_000));
+    }
+
+    #[test]
+    fn globs_and_types_narrow_the_files() {
+        let directory = Path::new("docs");
+        let filter = SearchFilter {
+            globs: vec!["notes/**".to_string()],
+            file_types: vec![".MD".to_string()],
+            ..SearchFilter::default()
+        };
+        let globs = search_globs(directory, &filter).unwrap();
+        assert!(filter_matches(&filter, &globs, &record("docs/notes/a.md", 0)));
+        assert!(!filter_matches(&filter, &globs, &record("docs/notes/a.txt", 0)));
+        assert!(!filter_matches(&filter, &globs, &record("docs/other/a.md", 0)));
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::metadata::{ChunkRecord, ChunkStore};
+
+/// File name of the keyword index inside `.vs/`.
+pub const KEYWORD_FILE_NAME: &str = "keywords.json";
+
+/// BM25 term frequency saturation.
+const K1: f32 = 1.2;
+
+/// BM25 document length normalization.
+const B: f32 = 0.75;
+
+/// Split text into lowercase search terms.
+///
+/// Identifiers, error codes and part numbers such as `ERR-4021`, `parse_args` or
+/// `v2.3.1` are kept whole, and their parts separated by `-`, `_` or `.` are added
+/// as terms of their own so that `4021` also finds `ERR-4021`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let is_joiner = |c: char| matches!(c, '-' | '_' | '.');
+    let mut terms = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric() && !is_joiner(c)) {
+        let word = word.trim_matches(is_joiner);
+        if word.is_empty() {
+            continue;
+        }
+        let word = word.to_lowercase();
+        if word.contains(is_joiner) {
+            terms.extend(word.split(is_joiner).filter(|part| !part.is_empty()).map(str::to_string));
+        }
+        terms.push(word);
+    }
+    terms
+}
+
+/// An inverted index over the chunk texts, scored with BM25, so that searches
+/// find exact terms an embedding may miss.
+#[derive(Default, Serialize, Deserialize)]
+pub struct KeywordIndex {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Number of terms in each chunk, by chunk id.
+    lengths: HashMap<u64, u32>,
+    /// Chunk ids and term frequencies of the chunks containing each term.
+    postings: HashMap<String, Vec<(u64, u32)>>,
+}
+
+impl KeywordIndex {
+    /// Index the contextual text of `records`, to be saved in `vs_dir`.
+    pub fn build(vs_dir: &Path, records: &[ChunkRecord]) -> Self {
+        let mut index = KeywordIndex { path: vs_dir.join(KEYWORD_FILE_NAME), ..KeywordIndex::default() };
+        for record in records {
+            let terms = tokenize(&record.contextual_text());
+            index.lengths.insert(record.id, terms.len() as u32);
+            let mut counts: HashMap<String, u32> = HashMap::new();
+            for term in terms {
+                *counts.entry(term).or_insert(0) += 1;
+            }
+            for (term, count) in counts {
+                index.postings.entry(term).or_default().push((record.id, count));
+            }
+        }
+        index
+    }
+
+    /// Load the index saved in `vs_dir`, or build and save it from `store` if it is
+    /// missing or does not cover exactly the chunks in the store.
+    pub fn open(vs_dir: &Path, store: &ChunkStore) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let path = vs_dir.join(KEYWORD_FILE_NAME);
+        let saved = match File::open(&path) {
+            Ok(file) => Some(serde_json::from_reader::<_, KeywordIndex>(BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        match saved {
+            Some(index) if index.covers(store) => Ok(KeywordIndex { path, ..index }),
+            _ => {
+                let index = KeywordIndex::build(vs_dir, store.records());
+                index.save()?;
+                Ok(index)
+            }
+        }
+    }
+
+    /// Whether the index holds exactly the chunks in `store`.
+    fn covers(&self, store: &ChunkStore) -> bool {
+        self.lengths.len() == store.records().len() && store.records().iter().all(|r| self.lengths.contains_key(&r.id))
+    }
+
+    /// Write the index to disk, replacing the previous file.
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Ids and BM25 scores of every chunk containing a term of `query`, best first.
+    pub fn search(&self, query: &str) -> Vec<(u64, f32)> {
+        let chunks = self.lengths.len() as f32;
+        if chunks == 0.0 {
+            return Vec::new();
+        }
+        let average_length = self.lengths.values().map(|&l| l as f32).sum::<f32>() / chunks;
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+
+        let mut scores: HashMap<u64, f32> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let containing = postings.len() as f32;
+            let idf = (1.0 + (chunks - containing + 0.5) / (containing + 0.5)).ln();
+            for &(id, count) in postings {
+                let count = count as f32;
+                let length = self.lengths.get(&id).copied().unwrap_or_default() as f32;
+                let norm = K1 * (1.0 - B + B * length / average_length.max(1.0));
+                *scores.entry(id).or_insert(0.0) += idf * count * (K1 + 1.0) / (count + norm);
+            }
+        }
+        let mut ranked: Vec<(u64, f32)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, text: &str) -> ChunkRecord {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "file": "docs/a.txt", "chunk": id, "text": text, "start": 0, "end": text.len(),
+            "file_hash": "", "mtime": 0, "embedding_model": "hash-8",
+        }))
+        .unwrap()
+    }
+
+    fn ids(hits: &[(u64, f32)]) -> Vec<u64> {
+        hits.iter().map(|&(id, _)| id).collect()
+    }
+
+    #[test]
+    fn identifiers_are_kept_whole_and_split() {
+        assert_eq!(tokenize("v2.3.1"), ["v2", "3", "1", "v2.3.1"]);
+        assert_eq!(tokenize("Call parse_args."), ["call", "parse", "args", "parse_args"]);
+        assert_eq!(tokenize("ERR-4021: Ünïcode"), ["err", "4021", "err-4021", "ünïcode"]);
+        assert!(tokenize(" -- ... ").is_empty());
+    }
+
+    #[test]
+    fn parts_of_an_error_code_find_it() {
+        let index = KeywordIndex::build(Path::new(""), &[record(1, "Failed with ERR-4021"), record(2, "Failed with ERR-5000")]);
+        assert_eq!(ids(&index.search("4021")), [1]);
+        // The shared `err` part also finds the other code, ranked below the exact match
+        for query in ["err-4021", "ERR-4021"] {
+            let hits = index.search(query);
+            assert_eq!(ids(&hits), [1, 2]);
+            assert!(hits[0].1 > hits[1].1);
+        }
+        assert!(index.search("4022").is_empty());
+    }
+
+    #[test]
+    fn rarer_terms_score_higher() {
+        let records = [record(1, "apple banana"), record(2, "apple cherry"), record(3, "apple cherry")];
+        let index = KeywordIndex::build(Path::new(""), &records);
+        let hits = index.search("banana cherry");
+        assert_eq!(ids(&hits), [1, 2, 3]);
+        assert!(hits[0].1 > hits[1].1);
+        assert_eq!(hits[1].1, hits[2].1);
+        assert!(KeywordIndex::default().search("apple").is_empty());
+    }
+
+    #[test]
+    fn open_rebuilds_and_saves_a_stale_index() {
+        let vs_dir = std::env::temp_dir().join(format!("fisher-keywords-{}", std::process::id()));
+        fs::create_dir_all(&vs_dir).unwrap();
+        let records = vec![record(1, "alpha"), record(2, "beta")];
+        KeywordIndex::build(&vs_dir, &records[..1]).save().unwrap();
+        let mut store = ChunkStore::open(&vs_dir).unwrap();
+        store.insert(records);
+
+        let index = KeywordIndex::open(&vs_dir, &store).unwrap();
+        assert_eq!(ids(&index.search("beta")), [2]);
+        let saved: KeywordIndex = serde_json::from_reader(File::open(vs_dir.join(KEYWORD_FILE_NAME)).unwrap()).unwrap();
+        assert!(saved.covers(&store));
+        fs::remove_dir_all(&vs_dir).unwrap();
+    }
+}
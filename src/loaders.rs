@@ -0,0 +1,1070 @@
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::Value;
+use zip::ZipArchive;
+use crate::config::{RecordOptions, fallback_encoding};
+
+/// Text extracted from a file, with what is known about where it came from.
+#[derive(Debug, Default)]
+pub struct Document {
+    pub text: String,
+    /// Byte offset in `text` where each page starts, for paged formats such as PDF.
+    pub page_starts: Vec<usize>,
+    /// Byte offset in `text` where each record starts with its 1-based index, for
+    /// structured data such as CSV rows or JSON records.
+    pub record_starts: Vec<(usize, usize)>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// Whether `text` was converted from another format, so its line numbers do
+    /// not point into the original file.
+    pub extracted: bool,
+}
+
+impl Document {
+    /// 1-based first and last page of the byte range `start..end`, for paged documents.
+    pub fn page_range(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        if self.page_starts.is_empty() {
+            return None;
+        }
+        let first = self.page_starts.partition_point(|&p| p <= start).max(1);
+        let last = self.page_starts.partition_point(|&p| p < end).max(first);
+        Some((first, last))
+    }
+
+    /// Indices of the first and last record in the byte range `start..end`, for structured data.
+    pub fn record_range(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        let first = self.record_starts.partition_point(|&(p, _)| p <= start).max(1);
+        let last = self.record_starts.partition_point(|&(p, _)| p < end).max(first);
+        Some((self.record_starts.get(first - 1)?.1, self.record_starts.get(last - 1)?.1))
+    }
+}
+
+/// Turns the bytes of one file format into a `Document`.
+pub trait DocumentLoader: Send + Sync {
+    /// Lowercase file extensions handled by this loader.
+    fn extensions(&self) -> &[&str];
+
+    /// Whether the first bytes of a file identify this loader's format.
+    fn sniff(&self, _head: &[u8]) -> bool {
+        false
+    }
+
+    /// Whether the format is binary, so files should not be rejected for containing NUL bytes.
+    fn binary(&self) -> bool {
+        true
+    }
+
+    /// Settings that change the documents this loader produces, such as the encoding
+    /// assumed for text, which re-chunk already indexed files when they change.
+    fn settings(&self) -> String {
+        String::new()
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Loaders to pick from by file extension, then by magic bytes.
+pub struct LoaderRegistry {
+    loaders: Vec<Box<dyn DocumentLoader>>,
+    /// Used for files no other loader claims.
+    fallback: Box<dyn DocumentLoader>,
+}
+
+impl LoaderRegistry {
+    /// A registry that reads every file as plain text.
+    pub fn new() -> Self {
+        Self {
+            loaders: Vec::new(),
+            fallback: Box::new(TextLoader),
+        }
+    }
+
+    /// Add a loader, taking precedence over those already registered.
+    pub fn register(&mut self, loader: Box<dyn DocumentLoader>) {
+        self.loaders.insert(0, loader);
+    }
+
+    /// The loader for a file with the given path and leading bytes.
+    pub fn loader_for(&self, path: &Path, head: &[u8]) -> &dyn DocumentLoader {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        self.loaders
+            .iter()
+            .find(|l| l.extensions().contains(&extension.as_str()))
+            .or_else(|| self.loaders.iter().find(|l| l.sniff(head)))
+            .map_or(self.fallback.as_ref(), |l| l.as_ref())
+    }
+
+    /// Read `path` with the loader for its format.
+    pub fn load(&self, path: &Path) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        let bytes = std::fs::read(path)?;
+        let head = &bytes[..bytes.len().min(HEAD_LEN)];
+        self.loader_for(path, head).load(&bytes)
+    }
+}
+
+impl Default for LoaderRegistry {
+    /// A registry with loaders for PDF, DOCX, ODT, EPUB, HTML, CSV and JSON.
+    fn default() -> Self {
+        let options = RecordOptions::from_env();
+        let mut registry = LoaderRegistry::new();
+        registry.register(Box::new(CsvLoader { options: options.clone() }));
+        registry.register(Box::new(JsonLoader { options }));
+        registry.register(Box::new(PdfLoader));
+        registry.register(Box::new(DocxLoader));
+        registry.register(Box::new(OdtLoader));
+        registry.register(Box::new(EpubLoader));
+        registry.register(Box::new(HtmlLoader));
+        registry
+    }
+}
+
+/// Number of leading bytes passed to `DocumentLoader::sniff`.
+pub const HEAD_LEN: usize = 8192;
+
+static REGISTRY: Lazy<LoaderRegistry> = Lazy::new(LoaderRegistry::default);
+
+/// The registry used when indexing.
+pub fn registry() -> &'static LoaderRegistry {
+    &REGISTRY
+}
+
+static FALLBACK_ENCODING: Lazy<&'static Encoding> = Lazy::new(fallback_encoding);
+
+/// Decode text that may not be UTF-8. A byte order mark selects UTF-8 or UTF-16,
+/// otherwise valid UTF-8 is kept as is and anything else is read in `declared`, or
+/// failing that the fallback encoding, replacing bytes that do not decode.
+pub fn decode_text<'a>(bytes: &'a [u8], declared: Option<&'static Encoding>) -> Cow<'a, str> {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding.decode_with_bom_removal(bytes).0;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => declared.unwrap_or(*FALLBACK_ENCODING).decode_without_bom_handling(bytes).0,
+    }
+}
+
+/// Whether the first bytes of a file look like binary data rather than text: a NUL
+/// byte, or many control characters, outside of a UTF-16 file with a byte order mark.
+pub fn looks_binary(head: &[u8]) -> bool {
+    if Encoding::for_bom(head).is_some() {
+        return false;
+    }
+    let control = head
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0C | 0x1B))
+        .count();
+    head.contains(&0) || control * 10 > head.len()
+}
+
+/// Reads files as text, transcoding legacy encodings to UTF-8.
+pub struct TextLoader;
+
+impl DocumentLoader for TextLoader {
+    fn extensions(&self) -> &[&str] {
+        &[]
+    }
+
+    fn binary(&self) -> bool {
+        false
+    }
+
+    fn settings(&self) -> String {
+        FALLBACK_ENCODING.name().to_string()
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Document {
+            text: decode_text(bytes, None).into_owned(),
+            ..Document::default()
+        })
+    }
+}
+
+/// Extracts PDF text page by page, along with the title and author.
+///
+/// Encrypted PDFs are tried with an empty password. Files that cannot be parsed, or
+/// that have no text layer (such as scans), are reported as errors.
+pub struct PdfLoader;
+
+impl DocumentLoader for PdfLoader {
+    fn extensions(&self) -> &[&str] {
+        &["pdf"]
+    }
+
+    fn sniff(&self, head: &[u8]) -> bool {
+        head.starts_with(b"%PDF-")
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        // pdf-extract panics on some malformed files rather than returning an error
+        let pages = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(bytes))
+            .map_err(|_| "PDF could not be parsed")?
+            .map_err(|e| format!("unreadable PDF: {}", e))?;
+
+        let mut document = Document { extracted: true, ..Document::default() };
+        for page in pages {
+            if !document.text.is_empty() {
+                document.text.push_str("\n\n");
+            }
+            document.page_starts.push(document.text.len());
+            document.text.push_str(&page);
+        }
+        if document.text.trim().is_empty() {
+            return Err("PDF has no extractable text".into());
+        }
+
+        if let Ok(pdf) = pdf_extract::Document::load_mem(bytes) {
+            document.title = pdf_info_field(&pdf, b"Title");
+            document.author = pdf_info_field(&pdf, b"Author");
+        }
+        Ok(document)
+    }
+}
+
+/// Read a text field such as `Title` from a PDF's document information dictionary.
+fn pdf_info_field(pdf: &pdf_extract::Document, key: &[u8]) -> Option<String> {
+    let info = match pdf.trailer.get(b"Info").ok()? {
+        pdf_extract::Object::Reference(id) => pdf.get_object(*id).ok()?,
+        info => info,
+    };
+    let pdf_extract::Object::String(bytes, _) = info.as_dict().ok()?.get(key).ok()? else {
+        return None;
+    };
+
+    // Text strings are UTF-16BE with a byte order mark, or PDFDocEncoding, which
+    // matches Latin-1 for the characters that matter here
+    let text: String = match bytes.strip_prefix(&[0xFE, 0xFF]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        None => bytes.iter().map(|&b| b as char).collect(),
+    };
+    non_empty(text)
+}
+
+/// Extracts the paragraphs of a Word document, marking headings as Markdown headings.
+pub struct DocxLoader;
+
+impl DocumentLoader for DocxLoader {
+    fn extensions(&self) -> &[&str] {
+        &["docx"]
+    }
+
+    fn sniff(&self, head: &[u8]) -> bool {
+        head.starts_with(b"PK\x03\x04") && contains(head, b"word/")
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+        let body = zip_entry(&mut archive, "word/document.xml")?;
+        let mut document = Document {
+            text: docx_text(&body)?,
+            extracted: true,
+            ..Document::default()
+        };
+        if let Ok(core) = zip_entry(&mut archive, "docProps/core.xml") {
+            document.title = xml_field(&core, &["title"]);
+            document.author = xml_field(&core, &["creator"]);
+        }
+        Ok(document)
+    }
+}
+
+/// Collect the paragraphs of `word/document.xml`, one per blank-line-separated block.
+fn docx_text(xml: &str) -> Result<String, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut text = String::new();
+    let mut paragraph = String::new();
+    let mut heading: Option<usize> = None;
+    let mut in_text = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.local_name().as_ref() == b"p" => {
+                paragraph.clear();
+                heading = None;
+            }
+            Event::Start(e) if e.local_name().as_ref() == b"t" => in_text = true,
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"pStyle" => heading = attribute(&e, b"val").and_then(|style| heading_level(&style)),
+                b"tab" => paragraph.push('\t'),
+                b"br" | b"cr" => paragraph.push('\n'),
+                _ => {}
+            },
+            Event::Text(e) if in_text => paragraph.push_str(&e.unescape()?),
+            Event::End(e) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"p" => push_block(&mut text, heading, &paragraph),
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(text)
+}
+
+/// Heading level of a Word paragraph style such as `Heading2` or `Title`.
+fn heading_level(style: &str) -> Option<usize> {
+    let style = style.to_lowercase();
+    if style == "title" {
+        return Some(1);
+    }
+    let level: usize = style.strip_prefix("heading")?.trim().parse().ok()?;
+    Some(level.clamp(1, 6))
+}
+
+/// Extracts the paragraphs of an OpenDocument text file, marking headings as Markdown headings.
+pub struct OdtLoader;
+
+impl DocumentLoader for OdtLoader {
+    fn extensions(&self) -> &[&str] {
+        &["odt"]
+    }
+
+    fn sniff(&self, head: &[u8]) -> bool {
+        head.starts_with(b"PK\x03\x04") && contains(head, b"mimetypeapplication/vnd.oasis.opendocument.text")
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+        let content = zip_entry(&mut archive, "content.xml")?;
+        let mut document = Document {
+            text: odt_text(&content)?,
+            extracted: true,
+            ..Document::default()
+        };
+        if let Ok(meta) = zip_entry(&mut archive, "meta.xml") {
+            document.title = xml_field(&meta, &["title"]);
+            document.author = xml_field(&meta, &["creator", "initial-creator"]);
+        }
+        Ok(document)
+    }
+}
+
+/// Collect the paragraphs and headings of an ODT `content.xml`.
+fn odt_text(xml: &str) -> Result<String, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut text = String::new();
+    let mut paragraph = String::new();
+    let mut heading: Option<usize> = None;
+    // Paragraphs can nest, as in notes; text belongs to the outermost one
+    let mut depth = 0;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"p" | b"h" => {
+                    if depth == 0 {
+                        paragraph.clear();
+                        heading = (e.local_name().as_ref() == b"h").then(|| {
+                            attribute(&e, b"outline-level")
+                                .and_then(|level| level.parse().ok())
+                                .unwrap_or(1usize)
+                                .clamp(1, 6)
+                        });
+                    }
+                    depth += 1;
+                }
+                b"s" if depth > 0 => paragraph.push(' '),
+                _ => {}
+            },
+            Event::Empty(e) if depth > 0 => match e.local_name().as_ref() {
+                b"s" => {
+                    let count = attribute(&e, b"c").and_then(|c| c.parse().ok()).unwrap_or(1);
+                    paragraph.push_str(&" ".repeat(count));
+                }
+                b"tab" => paragraph.push('\t'),
+                b"line-break" => paragraph.push('\n'),
+                _ => {}
+            },
+            Event::Text(e) if depth > 0 => paragraph.push_str(&e.unescape()?),
+            Event::End(e) if matches!(e.local_name().as_ref(), b"p" | b"h") => {
+                depth -= 1;
+                if depth == 0 {
+                    push_block(&mut text, heading, &paragraph);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(text)
+}
+
+/// Extracts the chapters of an EPUB in reading order.
+pub struct EpubLoader;
+
+impl DocumentLoader for EpubLoader {
+    fn extensions(&self) -> &[&str] {
+        &["epub"]
+    }
+
+    fn sniff(&self, head: &[u8]) -> bool {
+        head.starts_with(b"PK\x03\x04") && contains(head, b"mimetypeapplication/epub+zip")
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+        let container = zip_entry(&mut archive, "META-INF/container.xml")?;
+        let package_path = xml_attribute(&container, b"rootfile", b"full-path")
+            .ok_or("EPUB has no package document")?;
+        let package = zip_entry(&mut archive, &package_path)?;
+        let base = match package_path.rsplit_once('/') {
+            Some((dir, _)) => format!("{}/", dir),
+            None => String::new(),
+        };
+
+        let mut document = Document {
+            title: xml_field(&package, &["title"]),
+            author: xml_field(&package, &["creator"]),
+            extracted: true,
+            ..Document::default()
+        };
+        for href in epub_spine(&package)? {
+            // A broken chapter should not cost the rest of the book
+            let Ok(chapter) = zip_entry(&mut archive, &format!("{}{}", base, href)) else {
+                continue;
+            };
+            let chapter = html_to_text(&chapter);
+            if !chapter.text.is_empty() {
+                if !document.text.is_empty() {
+                    document.text.push_str("\n\n");
+                }
+                document.text.push_str(&chapter.text);
+            }
+        }
+        Ok(document)
+    }
+}
+
+/// Paths of the content documents listed in an EPUB package's spine, in reading order.
+fn epub_spine(package: &str) -> Result<Vec<String>, quick_xml::Error> {
+    let mut reader = Reader::from_str(package);
+    let mut manifest = Vec::new();
+    let mut spine = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"item" => {
+                    if let (Some(id), Some(href)) = (attribute(&e, b"id"), attribute(&e, b"href")) {
+                        manifest.push((id, href));
+                    }
+                }
+                b"itemref" => spine.extend(attribute(&e, b"idref")),
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(spine
+        .iter()
+        .filter_map(|idref| manifest.iter().find(|(id, _)| id == idref))
+        .map(|(_, href)| href.replace("%20", " "))
+        .collect())
+}
+
+/// Strips markup from HTML, keeping headings as Markdown headings.
+pub struct HtmlLoader;
+
+impl DocumentLoader for HtmlLoader {
+    fn extensions(&self) -> &[&str] {
+        &["html", "htm", "xhtml"]
+    }
+
+    fn sniff(&self, head: &[u8]) -> bool {
+        let head = String::from_utf8_lossy(head).trim_start().to_lowercase();
+        head.starts_with("<!doctype html") || head.starts_with("<html")
+    }
+
+    fn binary(&self) -> bool {
+        false
+    }
+
+    fn settings(&self) -> String {
+        FALLBACK_ENCODING.name().to_string()
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        let head = &bytes[..bytes.len().min(1024)];
+        Ok(html_to_text(&decode_text(bytes, declared_charset(head))))
+    }
+}
+
+/// The encoding named by a `charset=` declaration near the start of an HTML file.
+fn declared_charset(head: &[u8]) -> Option<&'static Encoding> {
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let label = &head[head.find("charset=")? + "charset=".len()..];
+    let label = label.trim_start_matches(['"', '\'']);
+    let end = label
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(label.len());
+    Encoding::for_label(&label.as_bytes()[..end])
+}
+
+/// Turns each CSV row into a line of `header: value` pairs.
+pub struct CsvLoader {
+    pub options: RecordOptions,
+}
+
+impl DocumentLoader for CsvLoader {
+    fn extensions(&self) -> &[&str] {
+        &["csv", "tsv"]
+    }
+
+    fn binary(&self) -> bool {
+        false
+    }
+
+    fn settings(&self) -> String {
+        format!("{} {:?}", FALLBACK_ENCODING.name(), self.options)
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        let text = decode_text(bytes, None);
+        // Tab separated files are told apart by their first line
+        let first_line = text.lines().next().unwrap_or_default();
+        let delimiter = if first_line.contains('\t') && !first_line.contains(',') { b'\t' } else { b',' };
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .flexible(true)
+            .from_reader(text.as_bytes());
+        let headers: Vec<String> = reader.headers()?.iter().map(|h| h.trim().to_string()).collect();
+
+        let mut records = Vec::new();
+        for row in reader.records() {
+            let row = row?;
+            let fields = row
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let name = headers.get(i).cloned().unwrap_or_else(|| format!("column {}", i + 1));
+                    (name, value.trim().to_string())
+                })
+                .collect();
+            records.push(fields);
+        }
+        Ok(records_document(records, &self.options))
+    }
+}
+
+/// Turns JSON arrays of objects and JSON Lines records into lines of `field: value`
+/// pairs, with nested fields named by their path, as in `author.name`.
+pub struct JsonLoader {
+    pub options: RecordOptions,
+}
+
+impl DocumentLoader for JsonLoader {
+    fn extensions(&self) -> &[&str] {
+        &["json", "jsonl", "ndjson"]
+    }
+
+    fn binary(&self) -> bool {
+        false
+    }
+
+    fn settings(&self) -> String {
+        format!("{} {:?}", FALLBACK_ENCODING.name(), self.options)
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Document, Box<dyn std::error::Error + Send + Sync>> {
+        // A whole-file JSON value, or failing that one value per line
+        let text = decode_text(bytes, None);
+        let values = match serde_json::from_str::<Value>(&text) {
+            Ok(Value::Array(items)) => items,
+            Ok(value) => vec![value],
+            Err(_) => text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<Value>, _>>()?,
+        };
+
+        let records = values
+            .iter()
+            .map(|value| {
+                let mut fields = Vec::new();
+                flatten_json("", value, &mut fields);
+                fields
+            })
+            .collect();
+        Ok(records_document(records, &self.options))
+    }
+}
+
+/// Collect the scalar fields of a JSON value under dotted paths.
+fn flatten_json(path: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    let child = |key: &str| if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) };
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_json(&child(key), value, fields);
+            }
+        }
+        Value::Array(items) if items.iter().all(|v| !v.is_object() && !v.is_array()) => {
+            let values: Vec<String> = items.iter().map(json_scalar).collect();
+            fields.push((path.to_string(), values.join(", ")));
+        }
+        Value::Array(items) => {
+            for (i, value) in items.iter().enumerate() {
+                flatten_json(&child(&i.to_string()), value, fields);
+            }
+        }
+        scalar => fields.push((if path.is_empty() { "value".to_string() } else { path.to_string() }, json_scalar(scalar))),
+    }
+}
+
+/// A JSON scalar as plain text, without quotes around strings.
+fn json_scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Lay out records one per line as `field: value; field: value`, keeping only the
+/// selected fields, with `options.group` records per blank-line-separated block.
+fn records_document(records: Vec<Vec<(String, String)>>, options: &RecordOptions) -> Document {
+    let mut document = Document { extracted: true, ..Document::default() };
+    let mut in_group = 0;
+    for (i, fields) in records.into_iter().enumerate() {
+        let line: Vec<String> = fields
+            .into_iter()
+            .filter(|(name, value)| !value.is_empty() && options.selects(name))
+            .map(|(name, value)| format!("{}: {}", name, value.replace('\n', " ")))
+            .collect();
+        if line.is_empty() {
+            continue;
+        }
+        if !document.text.is_empty() {
+            document.text.push_str(if in_group == 0 { "\n\n" } else { "\n" });
+        }
+        document.record_starts.push((document.text.len(), i + 1));
+        document.text.push_str(&line.join("; "));
+        in_group = (in_group + 1) % options.group.max(1);
+    }
+    document
+}
+
+/// Convert HTML to plain text with Markdown headings, leaving out scripts and styles.
+/// Tolerates the malformed markup found in the wild rather than parsing it strictly.
+fn html_to_text(html: &str) -> Document {
+    let mut document = Document { extracted: true, ..Document::default() };
+    let mut text = String::new();
+    let mut title = String::new();
+    let mut in_title = false;
+    let mut in_pre = false;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        let Some(open) = rest.find('<') else {
+            push_html_text(&mut text, rest, in_pre);
+            break;
+        };
+        let (before, tag_start) = rest.split_at(open);
+        if in_title {
+            title.push_str(before);
+        } else {
+            push_html_text(&mut text, before, in_pre);
+        }
+
+        if let Some(comment) = tag_start.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(close) = tag_start.find('>') else {
+            break;
+        };
+        let tag = &tag_start[1..close];
+        rest = &tag_start[close + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        match (name.as_str(), closing) {
+            ("script" | "style" | "noscript" | "template", false) => {
+                // Skip to the matching end tag
+                rest = find_end_tag(rest, &name).map_or("", |i| &rest[i..]);
+            }
+            ("title", _) => in_title = !closing,
+            ("pre", _) => {
+                in_pre = !closing;
+                text.push_str("\n\n");
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+                let level = name[1..].parse().unwrap_or(1);
+                text.push_str("\n\n");
+                text.push_str(&"#".repeat(level));
+                text.push(' ');
+            }
+            ("br", _) => text.push('\n'),
+            ("li", false) => text.push_str("\n- "),
+            ("td" | "th", false) => text.push(' '),
+            ("meta", false) => {
+                let attributes = html_attributes(tag);
+                let name = attributes.iter().find(|(k, _)| k == "name").map(|(_, v)| v.to_lowercase());
+                if name.as_deref() == Some("author") {
+                    document.author = attributes
+                        .into_iter()
+                        .find(|(k, _)| k == "content")
+                        .and_then(|(_, v)| non_empty(decode_entities(&v)));
+                }
+            }
+            (
+                "p" | "div" | "section" | "article" | "header" | "footer" | "nav" | "aside" | "main" | "blockquote"
+                | "ul" | "ol" | "table" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "hr" | "body",
+                _,
+            ) => text.push_str("\n\n"),
+            _ => {}
+        }
+    }
+
+    document.title = non_empty(decode_entities(&title));
+    document.text = tidy_blank_lines(&text);
+    document
+}
+
+/// Byte offset of the first `</name` in `html`, matching the name in any case.
+fn find_end_tag(html: &str, name: &str) -> Option<usize> {
+    let name = name.as_bytes();
+    html.as_bytes()
+        .windows(name.len() + 2)
+        .position(|window| window.starts_with(b"</") && window[2..].eq_ignore_ascii_case(name))
+}
+
+/// Append text from between tags, decoding entities and collapsing whitespace outside `<pre>`.
+fn push_html_text(text: &mut String, raw: &str, in_pre: bool) {
+    let decoded = decode_entities(raw);
+    if in_pre {
+        text.push_str(&decoded);
+        return;
+    }
+    for (i, word) in decoded.split_whitespace().enumerate() {
+        let at_line_start = text.is_empty() || text.ends_with(['\n', ' ', '\t']);
+        if i > 0 || (!at_line_start && decoded.starts_with(char::is_whitespace)) {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    if decoded.ends_with(char::is_whitespace) && !decoded.trim().is_empty() {
+        text.push(' ');
+    }
+}
+
+/// Parse the `name="value"` attributes of a tag's contents.
+fn html_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag.split_once(char::is_whitespace).map_or("", |(_, rest)| rest);
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].split_whitespace().last().unwrap_or("").to_lowercase();
+        let value_start = rest[eq + 1..].trim_start();
+        let (value, after) = match value_start.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let inner = &value_start[1..];
+                let end = inner.find(quote).unwrap_or(inner.len());
+                (&inner[..end], inner.get(end + 1..).unwrap_or(""))
+            }
+            _ => value_start.split_once(char::is_whitespace).unwrap_or((value_start, "")),
+        };
+        attributes.push((key, value.to_string()));
+        rest = after;
+    }
+    attributes
+}
+
+/// Decode the common named HTML entities and numeric character references.
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest[1..].find(';').filter(|&end| end <= 10).and_then(|end| {
+            let entity = &rest[1..end + 1];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match entity.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok().and_then(char::from_u32),
+                    Some(dec) => dec.parse().ok().and_then(char::from_u32),
+                    None => None,
+                },
+            };
+            c.map(|c| (c, end + 2))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Trim trailing spaces from lines and collapse runs of blank lines into one.
+fn tidy_blank_lines(text: &str) -> String {
+    let mut out = String::new();
+    let mut blank_run = 0;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            blank_run += 1;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank_run > 0 { "\n\n" } else { "\n" });
+        }
+        out.push_str(line);
+        blank_run = 0;
+    }
+    out
+}
+
+/// Append a paragraph as its own block, as a Markdown heading at `heading` level if given.
+fn push_block(text: &mut String, heading: Option<usize>, paragraph: &str) {
+    let paragraph = paragraph.trim();
+    if paragraph.is_empty() {
+        return;
+    }
+    if !text.is_empty() {
+        text.push_str("\n\n");
+    }
+    if let Some(level) = heading {
+        text.push_str(&"#".repeat(level));
+        text.push(' ');
+    }
+    text.push_str(paragraph);
+}
+
+/// Read a UTF-8 entry from a zip archive.
+fn zip_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut entry = archive.by_name(name)?;
+    let mut contents = String::new();
+    entry.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Text of the first element whose local name is one of `names`, such as `dc:title`.
+fn xml_field(xml: &str, names: &[&str]) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut capturing = false;
+    let mut value = String::new();
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) if names.iter().any(|n| n.as_bytes() == e.local_name().as_ref()) => capturing = true,
+            Event::Text(e) if capturing => value.push_str(&e.unescape().ok()?),
+            Event::End(_) if capturing => return non_empty(value),
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+}
+
+/// Value of `attribute` on the first `element` in `xml`.
+fn xml_attribute(xml: &str, element: &[u8], attribute_name: &[u8]) -> Option<String> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == element => {
+                return attribute(&e, attribute_name);
+            }
+            Event::Eof => return None,
+            _ => {}
+        }
+    }
+}
+
+/// Unescaped value of the attribute with the given local name.
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == name)
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.into_owned())
+}
+
+/// Whether `haystack` contains `needle`.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Trim `text`, treating an empty result as missing.
+fn non_empty(text: String) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    fn load(loader: &dyn DocumentLoader, name: &str) -> Document {
+        loader.load(&fixture(name)).unwrap_or_else(|e| panic!("{}: {}", name, e))
+    }
+
+    #[test]
+    fn registry_picks_loaders_by_extension_then_magic_bytes() {
+        let registry = LoaderRegistry::default();
+        let pick = |name: &str, head: &[u8]| registry.loader_for(Path::new(name), head).extensions().to_vec();
+        assert_eq!(pick("report.PDF", b""), ["pdf"]);
+        assert_eq!(pick("report", b"%PDF-1.7"), ["pdf"]);
+        assert_eq!(pick("book", &fixture("book.epub")), ["epub"]);
+        assert_eq!(pick("notes", &fixture("document.odt")), ["odt"]);
+        assert_eq!(pick("page", b"  <!DOCTYPE HTML>"), ["html", "htm", "xhtml"]);
+        assert!(pick("notes.txt", b"plain").is_empty());
+    }
+
+    #[test]
+    fn pdf_pages_and_metadata() {
+        let document = load(&PdfLoader, "two-pages.pdf");
+        assert_eq!(document.page_starts.len(), 2);
+        assert!(document.extracted);
+        let second = document.page_starts[1];
+        assert!(document.text[..second].contains("Alpha page one"));
+        assert!(document.text[second..].contains("Beta page two"));
+        assert_eq!(document.page_range(0, 5), Some((1, 1)));
+        assert_eq!(document.page_range(second, document.text.len()), Some((2, 2)));
+        assert_eq!(document.page_range(0, document.text.len()), Some((1, 2)));
+        assert_eq!(document.title.as_deref(), Some("Fixture Report"));
+        assert_eq!(document.author.as_deref(), Some("Ada Lovelace"));
+    }
+
+    #[test]
+    fn unreadable_pdf_is_an_error() {
+        assert!(PdfLoader.load(&fixture("unreadable.pdf")).is_err());
+        assert!(PdfLoader.load(b"").is_err());
+    }
+
+    #[test]
+    fn docx_paragraphs_and_headings() {
+        let document = load(&DocxLoader, "document.docx");
+        assert_eq!(document.text, "# Overview\n\nFish & chips\tdaily\n\nSecond\nline");
+        assert_eq!(document.title.as_deref(), Some("Word Fixture"));
+        assert_eq!(document.author.as_deref(), Some("Grace Hopper"));
+    }
+
+    #[test]
+    fn odt_paragraphs_headings_and_spaces() {
+        let document = load(&OdtLoader, "document.odt");
+        assert_eq!(document.text, "## Chapter\n\nTwo  spaces\tand a tab\n\nOuter inner note text");
+        assert_eq!(document.title.as_deref(), Some("Open Fixture"));
+        assert_eq!(document.author.as_deref(), Some("Alan Turing"));
+    }
+
+    #[test]
+    fn epub_chapters_in_spine_order() {
+        let document = load(&EpubLoader, "book.epub");
+        assert_eq!(document.text, "# First\n\nIt was a dark night.\n\n# Second\n\nThe end.");
+        assert_eq!(document.title.as_deref(), Some("Book Fixture"));
+        assert_eq!(document.author.as_deref(), Some("Mary Shelley"));
+    }
+
+    #[test]
+    fn html_strips_scripts_styles_and_comments() {
+        let document = load(&HtmlLoader, "page.html");
+        assert_eq!(document.title.as_deref(), Some("Café & Co"));
+        assert_eq!(document.author.as_deref(), Some("Jane Doe"));
+        for hidden in ["color", "not text", "hidden comment", "enable scripts"] {
+            assert!(!document.text.contains(hidden), "{:?} left in {:?}", hidden, document.text);
+        }
+        assert_eq!(document.text, "## Menu\n\nCrème brûlée <3\n\n- Tea\n- Coffee\n\n  keep   spacing");
+    }
+
+    #[test]
+    fn html_end_tags_match_in_any_case() {
+        assert_eq!(find_end_tag("a</ScRiPt>", "script"), Some(1));
+        assert_eq!(find_end_tag("</scrip", "script"), None);
+        assert_eq!(find_end_tag("é</style", "style"), Some(2));
+        let document = html_to_text("<p>kept</p><script>never</p></SCRIPT><p>after</p>");
+        assert_eq!(document.text, "kept\n\nafter");
+    }
+
+    #[test]
+    fn csv_rows_become_records() {
+        let loader = CsvLoader { options: RecordOptions::default() };
+        let document = load(&loader, "people.csv");
+        assert_eq!(
+            document.text,
+            "name: Ada; city: London; note: first\n\nname: Grace; city: New York; note: multi line\n\nname: Alan"
+        );
+        assert_eq!(document.record_starts.iter().map(|&(_, i)| i).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(document.record_range(0, document.text.len()), Some((1, 3)));
+
+        let tsv = load(&loader, "people.tsv");
+        assert_eq!(tsv.text, "name: Ada; city: London\n\nname: Grace; city: New York");
+    }
+
+    #[test]
+    fn records_are_grouped_and_fields_selected() {
+        let options = RecordOptions { group: 2, fields: vec!["city".to_string()] };
+        let document = load(&CsvLoader { options }, "people.csv");
+        // Alan has no city, so is left out along with his record number
+        assert_eq!(document.text, "city: London\ncity: New York");
+        assert_eq!(document.record_starts, [(0, 1), (13, 2)]);
+        assert_eq!(document.record_range(13, 27), Some((2, 2)));
+    }
+
+    #[test]
+    fn json_records_are_flattened() {
+        let loader = JsonLoader { options: RecordOptions::default() };
+        let document = load(&loader, "records.json");
+        assert_eq!(document.text, "id: 1; author.name: Ada; tags: math, code\n\nid: 2; author.name: Grace");
+
+        let options = RecordOptions { group: 2, fields: vec!["author".to_string()] };
+        let document = load(&JsonLoader { options }, "records.json");
+        assert_eq!(document.text, "author.name: Ada\nauthor.name: Grace");
+
+        let lines = load(&loader, "records.jsonl");
+        assert_eq!(lines.text, "id: 1; title: One\n\nid: 2; title: Two");
+        assert!(loader.load(b"{\"id\": 1}\nnot json\n").is_err());
+    }
+
+    #[test]
+    fn text_encodings_are_detected() {
+        assert_eq!(load(&TextLoader, "latin1.txt").text, "naïve café €5");
+        assert_eq!(load(&TextLoader, "utf16.txt").text, "wide text");
+        assert_eq!(decode_text("déjà".as_bytes(), None), "déjà");
+        assert_eq!(declared_charset(b"<meta charset=\"ISO-8859-1\">"), Some(encoding_rs::WINDOWS_1252));
+    }
+
+    #[test]
+    fn binary_files_are_told_apart_from_text() {
+        assert!(looks_binary(&fixture("binary.bin")));
+        assert!(!looks_binary(&fixture("latin1.txt")));
+        assert!(!looks_binary(&fixture("utf16.txt")));
+        assert!(!looks_binary(&fixture("people.csv")));
+        assert!(!looks_binary(b"colors: \x1b[31mred\x1b[0m\tand\x0cform feed\r\n"));
+        assert!(looks_binary(b"\x01\x02\x03 mostly control \x04\x05\x06\x07\x08"));
+    }
+}
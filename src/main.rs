@@ -1,8 +1,16 @@
 use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use clap::Parser;
 use dotenv::dotenv;
+use futures_util::StreamExt;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task::JoinHandle;
 
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    event::{DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyCode, KeyEventKind, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -14,29 +22,98 @@ use ratatui::{
 
 mod ui;
 use ui::chat_interface;
-use ui::chat_interface::ChatInterface;
+use ui::chat_interface::{ChatInterface, Message};
 use ui::home_screen::{HomeScreen, HomeScreenAction};
+use ui::indexing_screen::{IndexingAction, IndexingScreen};
 
 mod model;
-use model::{
-    generate_response,
-};
+use model::{ChatModel, chat_model_from_env};
 
 mod files;
-use files::setup_vector_store;
+use files::{DEFAULT_TOP_K, IndexProgress, SearchIndex, Source, indexed_dimension, retrieve_context, setup_vector_store};
 
 mod faiss;
 
+mod metadata;
+
+mod config;
+use config::SearchFilter;
+
+mod chunking;
+
+mod loaders;
+
+mod embedding;
+use embedding::{Embedder, embedder_from_env};
+
+mod cli;
+mod cache;
+mod keyword;
+use cli::Cli;
+
+type AppTerminal = Terminal<CrosstermBackend<io::Stdout>>;
+
+/// Messages posted to the UI loop by background tasks.
+enum AppEvent {
+    /// Progress from the indexing task.
+    Progress(IndexProgress),
+    /// Indexing finished, with what to search the index with, or `None` if the run
+    /// was cancelled.
+    Indexed(Result<Option<Retriever>, String>),
+    /// A piece of the reply with the given id.
+    ReplyToken(u64, String),
+    /// The reply finished; carries the sources it was grounded in.
+    ReplyDone(u64, Vec<Source>),
+    ReplyFailed(u64, String),
+}
+
+/// What questions are answered from: the embedder for queries and the index,
+/// loaded once for the whole chat.
+#[derive(Clone)]
+struct Retriever {
+    embedder: Arc<dyn Embedder>,
+    index: Arc<SearchIndex>,
+}
+
+/// Where the indexing screen sends the user next.
+enum IndexingOutcome {
+    /// Chat about the directory, searching it with this retriever.
+    Chat(Retriever),
+    /// Go back to the home screen.
+    Back,
+    Quit,
+}
+
+/// A reply being produced by a background task.
+struct PendingReply {
+    id: u64,
+    task: JoinHandle<()>,
+    /// Position of the reply in the chat history.
+    index: usize,
+    /// Whether any text has arrived yet.
+    started: bool,
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    
-    if let Err(e) = run_app().await {
-        eprintln!("App error: {}", e);
+
+    match Cli::parse().command {
+        Some(command) => {
+            if let Err(e) = cli::run(command).await {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        None => {
+            if let Err(e) = run_app().await {
+                eprintln!("App error: {}", e);
+            }
+        }
     }
 }
 
-/// Main application loop with home screen and chat
+/// Main application loop: home screen, then indexing, then chat
 async fn run_app() -> Result<(), io::Error> {
     // Terminal initialization
     enable_raw_mode()?;
@@ -45,104 +122,188 @@ async fn run_app() -> Result<(), io::Error> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
+    let mut events = EventStream::new();
+    let result = run_screens(&mut terminal, &mut events).await;
+
+    // Restore terminal
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+
+    result
+}
+
+/// Move between the screens until the user quits.
+async fn run_screens(terminal: &mut AppTerminal, events: &mut EventStream) -> Result<(), io::Error> {
     let mut home_screen = HomeScreen::new();
-    let mut current_directory = home_screen.get_directory();
 
-    // Home screen loop
+    loop {
+        let Some(directory) = run_home_screen(terminal, events, &mut home_screen).await? else {
+            return Ok(());
+        };
+        match run_indexing(terminal, events, directory).await? {
+            IndexingOutcome::Chat(retriever) => return run_chat(terminal, events, retriever).await,
+            IndexingOutcome::Back => {}
+            IndexingOutcome::Quit => return Ok(()),
+        }
+    }
+}
+
+/// Run the home screen until the user starts a chat (returning the directory) or quits.
+async fn run_home_screen(
+    terminal: &mut AppTerminal,
+    events: &mut EventStream,
+    home_screen: &mut HomeScreen,
+) -> Result<Option<PathBuf>, io::Error> {
     loop {
         terminal.draw(|f| {
             home_screen.render(f);
         })?;
 
-        if let Event::Key(key) = event::read()? {
-            if key.kind == KeyEventKind::Press {
-                match key.code {
-                    KeyCode::Char(c) => {
-                        let action = home_screen.handle_input(c);
-                        match action {
-                            HomeScreenAction::StartChat => {
-                                current_directory = home_screen.get_directory();
-                                break;
-                            }
-                            HomeScreenAction::Quit => {
-                                // Restore terminal and exit
-                                disable_raw_mode()?;
-                                execute!(
-                                    terminal.backend_mut(),
-                                    LeaveAlternateScreen,
-                                    DisableMouseCapture
-                                )?;
-                                terminal.show_cursor()?;
-                                return Ok(());
+        let Some(event) = events.next().await else {
+            return Ok(None);
+        };
+        if let Event::Key(key) = event?
+            && key.kind == KeyEventKind::Press
+        {
+            let action = match key.code {
+                KeyCode::Char(c) => home_screen.handle_input(c),
+                KeyCode::Backspace => home_screen.handle_input('\x08'),
+                KeyCode::Enter => home_screen.handle_input('\n'),
+                KeyCode::Esc => HomeScreenAction::Quit,
+                _ => HomeScreenAction::Continue,
+            };
+            match action {
+                HomeScreenAction::StartChat => return Ok(Some(home_screen.get_directory())),
+                HomeScreenAction::Quit => return Ok(None),
+                HomeScreenAction::Continue => {}
+            }
+        }
+    }
+}
+
+/// Index `directory` in the background while showing its progress.
+async fn run_indexing(terminal: &mut AppTerminal, events: &mut EventStream, directory: PathBuf) -> Result<IndexingOutcome, io::Error> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut screen = IndexingScreen::new(&directory.display().to_string());
+    let cancel = Arc::new(AtomicBool::new(false));
+    let task = spawn_indexing(directory, tx, cancel.clone());
+    let mut retriever: Option<Retriever> = None;
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+
+    let outcome = loop {
+        terminal.draw(|f| {
+            screen.render(f);
+        })?;
+
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break IndexingOutcome::Quit;
+                };
+                if let Event::Key(key) = event?
+                    && key.kind == KeyEventKind::Press
+                {
+                    let action = match key.code {
+                        KeyCode::Char(c) => screen.handle_input(c),
+                        KeyCode::Enter => screen.handle_input('\n'),
+                        KeyCode::Esc => screen.handle_input('\x1b'),
+                        _ => IndexingAction::Continue,
+                    };
+                    match action {
+                        // The task stops after the current file and reports back
+                        IndexingAction::Cancel => cancel.store(true, Ordering::Relaxed),
+                        IndexingAction::StartChat => {
+                            if let Some(retriever) = retriever.take() {
+                                break IndexingOutcome::Chat(retriever);
                             }
-                            HomeScreenAction::Continue => {}
-                        }
-                    }
-                    KeyCode::Backspace => {
-                        let action = home_screen.handle_input('\x08');
-                        if action == HomeScreenAction::Quit {
-                            // Restore terminal and exit
-                            disable_raw_mode()?;
-                            execute!(
-                                terminal.backend_mut(),
-                                LeaveAlternateScreen,
-                                DisableMouseCapture
-                            )?;
-                            terminal.show_cursor()?;
-                            return Ok(());
                         }
+                        IndexingAction::Back => break IndexingOutcome::Back,
+                        IndexingAction::Continue => {}
                     }
-                    KeyCode::Enter => {
-                        let action = home_screen.handle_input('\n');
-                        if action == HomeScreenAction::StartChat {
-                            current_directory = home_screen.get_directory();
-                            break;
-                        }
-                    }
-                    KeyCode::Esc => {
-                        // Restore terminal and exit
-                        disable_raw_mode()?;
-                        execute!(
-                            terminal.backend_mut(),
-                            LeaveAlternateScreen,
-                            DisableMouseCapture
-                        )?;
-                        terminal.show_cursor()?;
-                        return Ok(());
-                    }
-                    _ => {}
                 }
             }
+            Some(app_event) = rx.recv() => match app_event {
+                AppEvent::Progress(progress) => screen.apply(progress),
+                AppEvent::Indexed(Ok(ready)) => {
+                    let Some(ready) = ready else {
+                        break IndexingOutcome::Back;
+                    };
+                    screen.finish(Ok(()));
+                    // Only stop on this screen when there is something worth reading
+                    if !screen.has_problems() {
+                        break IndexingOutcome::Chat(ready);
+                    }
+                    retriever = Some(ready);
+                }
+                AppEvent::Indexed(Err(e)) => screen.finish(Err(e)),
+                _ => {}
+            },
+            _ = ticker.tick() => screen.tick(),
         }
-    }
+    };
 
+    task.abort();
+    Ok(outcome)
+}
 
-    // set up the vector store
-    setup_vector_store(current_directory);
+/// Run the chat, answering questions in background tasks so the interface
+/// keeps responding to input while they work.
+async fn run_chat(
+    terminal: &mut AppTerminal,
+    events: &mut EventStream,
+    retriever: Retriever,
+) -> Result<(), io::Error> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut chat = ChatInterface::new();
 
-    return Ok(());
+    let chat_model: Option<Arc<dyn ChatModel>> = match chat_model_from_env() {
+        Ok(chat_model) => {
+            chat.model_name = chat_model.name();
+            Some(Arc::from(chat_model))
+        }
+        Err(e) => {
+            chat.add_notice(&format!("Chat model unavailable: {}", e));
+            None
+        }
+    };
 
-    // Chat loop
-    let mut chat = ChatInterface::new();
+    let mut reply: Option<PendingReply> = None;
+    let mut next_reply_id = 0;
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
 
     loop {
-        let last_message = chat.get_last_message();
-        if let Some(message) = last_message {
-            if message.sender == "User" {
-                chat.add_message("LLM", "...");
-                terminal.draw(|f| {
-                    chat.render(f);
-                })?;
-                // Handle LLM response
-                match generate_response(&chat.messages).await {
-                    Ok(response) => {
-                        chat.messages.pop(); // Remove waiting message
-                        chat.add_message("LLM", &response);
-                    }
-                    Err(e) => {
-                        chat.messages.pop();
-                        chat.add_message("LLM", &format!("Error: {}", e));
-                    }
+        // Answer the latest question once no reply is in flight
+        let question = chat.pending_question().map(|m| m.content.clone());
+        if let (Some(question), None) = (question, &reply) {
+            match &chat_model {
+                Some(chat_model) => {
+                    let history = chat.messages.clone();
+                    chat.add_message("LLM", "...");
+                    chat.streaming = true;
+                    chat.status = Some("thinking".to_string());
+                    next_reply_id += 1;
+                    reply = Some(PendingReply {
+                        id: next_reply_id,
+                        task: spawn_reply(
+                            next_reply_id,
+                            question,
+                            history,
+                            retriever.clone(),
+                            chat_model.clone(),
+                            tx.clone(),
+                        ),
+                        index: chat.messages.len() - 1,
+                        started: false,
+                    });
+                }
+                None => {
+                    chat.add_message("LLM", "Error: cannot answer until the chat model is available");
+                    chat.set_message_incomplete(chat.messages.len() - 1);
                 }
             }
         }
@@ -151,29 +312,151 @@ async fn run_app() -> Result<(), io::Error> {
             chat.render(f);
         })?;
 
-        if let Event::Key(key) = event::read()? {
-            if key.kind == KeyEventKind::Press {
-                match key.code {
-                    KeyCode::Char(c) => chat.handle_input(c),
-                    KeyCode::Backspace => chat.handle_input('\x08'),
-                    KeyCode::Enter => chat.handle_input('\n'),
-                    KeyCode::Up => chat.scroll_up(),
-                    KeyCode::Down => chat.scroll_down(),
-                    KeyCode::Esc => break,
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                match event? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => match key.code {
+                        // Esc stops a streaming reply first, and quits otherwise
+                        KeyCode::Esc => match reply.take() {
+                            Some(pending) => {
+                                pending.task.abort();
+                                if pending.started {
+                                    chat.append_to_message(pending.index, " [stopped]");
+                                } else {
+                                    chat.set_message_content(pending.index, "[stopped]");
+                                }
+                                chat.set_message_incomplete(pending.index);
+                                chat.streaming = false;
+                                chat.status = None;
+                            }
+                            None => break,
+                        },
+                        KeyCode::Char(c) => chat.handle_input(c),
+                        KeyCode::Backspace => chat.handle_input('\x08'),
+                        KeyCode::Enter => chat.handle_input('\n'),
+                        KeyCode::Up => chat.scroll_up(),
+                        KeyCode::Down => chat.scroll_down(),
+                        _ => {}
+                    },
+                    Event::Mouse(mouse) => match mouse.kind {
+                        MouseEventKind::ScrollUp => chat.scroll_up(),
+                        MouseEventKind::ScrollDown => chat.scroll_down(),
+                        _ => {}
+                    },
+                    // Resizes only need a redraw, which happens every iteration
                     _ => {}
                 }
             }
+            Some(app_event) = rx.recv() => match app_event {
+                AppEvent::ReplyToken(id, text) => {
+                    if let Some(pending) = reply.as_mut().filter(|p| p.id == id) {
+                        if !pending.started {
+                            chat.set_message_content(pending.index, "");
+                            pending.started = true;
+                        }
+                        chat.append_to_message(pending.index, &text);
+                    }
+                }
+                AppEvent::ReplyDone(id, sources) => {
+                    if let Some(pending) = reply.take_if(|p| p.id == id) {
+                        if !pending.started {
+                            chat.set_message_content(pending.index, "");
+                        }
+                        chat.set_message_sources(pending.index, sources);
+                        chat.streaming = false;
+                        chat.status = None;
+                    }
+                }
+                AppEvent::ReplyFailed(id, e) => {
+                    if let Some(pending) = reply.take_if(|p| p.id == id) {
+                        if pending.started {
+                            chat.append_to_message(pending.index, &format!("\n\nError: {}", e));
+                        } else {
+                            chat.set_message_content(pending.index, &format!("Error: {}", e));
+                        }
+                        chat.set_message_incomplete(pending.index);
+                        chat.streaming = false;
+                        chat.status = None;
+                    }
+                }
+                _ => {}
+            },
+            _ = ticker.tick() => chat.tick(),
         }
     }
 
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
+    if let Some(pending) = reply {
+        pending.task.abort();
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Build the embedder and index `directory` in the background, reporting progress
+/// and stopping between files once `cancel` is set.
+fn spawn_indexing(directory: PathBuf, tx: UnboundedSender<AppEvent>, cancel: Arc<AtomicBool>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let progress_tx = tx.clone();
+        let progress = move |p: IndexProgress| {
+            let _ = progress_tx.send(AppEvent::Progress(p));
+        };
+        let result = match embedder_from_env(|model| indexed_dimension(&directory, model)).await {
+            Ok(embedder) => {
+                let embedder: Arc<dyn Embedder> = Arc::from(embedder);
+                match setup_vector_store(directory.clone(), embedder.as_ref(), &progress, &cancel).await {
+                    Ok(report) if report.cancelled => Ok(None),
+                    // Load the index once, to be searched for every question in the chat
+                    Ok(_) => SearchIndex::open(&directory, embedder.dimension())
+                        .map(|index| Some(Retriever { embedder, index: Arc::new(index) })),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let _ = tx.send(AppEvent::Indexed(result.map_err(|e| e.to_string())));
+    })
+}
+
+/// Retrieve context for `question` and stream the model's answer back to the UI.
+fn spawn_reply(
+    id: u64,
+    question: String,
+    history: Vec<Message>,
+    retriever: Retriever,
+    chat_model: Arc<dyn ChatModel>,
+    tx: UnboundedSender<AppEvent>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // Ground the answer in the chunks nearest to the question
+        let context = match retrieve_context(&question, &retriever.index, retriever.embedder.as_ref(), DEFAULT_TOP_K, &SearchFilter::from_env()).await {
+            Ok(context) => context,
+            Err(e) => {
+                let _ = tx.send(AppEvent::ReplyFailed(id, e.to_string()));
+                return;
+            }
+        };
+        let (texts, sources): (Vec<String>, Vec<Source>) = context.into_iter().unzip();
+
+        let mut stream = match chat_model.stream_response(&history, &texts).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = tx.send(AppEvent::ReplyFailed(id, e.to_string()));
+                return;
+            }
+        };
+        while let Some(piece) = stream.next().await {
+            match piece {
+                Ok(text) => {
+                    let _ = tx.send(AppEvent::ReplyToken(id, text));
+                }
+                Err(e) => {
+                    let _ = tx.send(AppEvent::ReplyFailed(id, e.to_string()));
+                    return;
+                }
+            }
+        }
+        let _ = tx.send(AppEvent::ReplyDone(id, sources));
+    })
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+/// Version of the on-disk layout of `chunks.jsonl`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// File name of the chunk metadata store inside `.vs/`.
+const STORE_FILE_NAME: &str = "chunks.jsonl";
+
+/// File name of the text lookup used before the metadata store existed.
+const LEGACY_LOOKUP_FILE_NAME: &str = "faiss_lookup.txt";
+
+/// First line of `chunks.jsonl`, describing the records that follow.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreHeader {
+    schema_version: u32,
+    /// FAISS factory string of the index the record ids refer to.
+    #[serde(default)]
+    index_description: String,
+    /// Embedding model the vectors in the index were made with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    embedding_model: Option<String>,
+    /// Length of the vectors in the index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dimension: Option<usize>,
+}
+
+impl Default for StoreHeader {
+    fn default() -> Self {
+        StoreHeader {
+            schema_version: SCHEMA_VERSION,
+            index_description: String::new(),
+            embedding_model: None,
+            dimension: None,
+        }
+    }
+}
+
+/// Everything known about the chunk behind one vector in the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkRecord {
+    /// Id of the vector in the FAISS index.
+    pub id: u64,
+    pub file: String,
+    /// Position of the chunk within its file.
+    pub chunk: usize,
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+    /// Heading breadcrumb of the section the chunk is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<String>,
+    /// Name of the function, type or other item the chunk is part of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    /// 1-based first and last line of the chunk in its file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines: Option<(usize, usize)>,
+    /// 1-based first and last page of the chunk, for paged documents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pages: Option<(usize, usize)>,
+    /// 1-based indices of the first and last row or record in the chunk, for structured data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub records: Option<(usize, usize)>,
+    /// Title of the document, from its own metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Author of the document, from its own metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// SHA-256 of the file contents when the chunk was embedded.
+    pub file_hash: String,
+    /// Fingerprint of the chunking settings the file was split with, see
+    /// `chunking_fingerprint`; empty for chunks from before it was recorded.
+    #[serde(default)]
+    pub chunking: String,
+    /// Modification time of the file in seconds since the Unix epoch.
+    pub mtime: u64,
+    pub embedding_model: String,
+}
+
+impl ChunkRecord {
+    /// The chunk text as embedded and shown to the model, prefixed with the document's
+    /// title and author and the chunk's heading breadcrumb or symbol name, so it reads
+    /// on its own and those fields can be searched.
+    pub fn contextual_text(&self) -> String {
+        let mut labels = Vec::new();
+        if let Some(title) = &self.title {
+            labels.push(format!("Title: {}", title));
+        }
+        if let Some(author) = &self.author {
+            labels.push(format!("Author: {}", author));
+        }
+        if let Some(label) = self.heading.as_ref().or(self.symbol.as_ref()) {
+            labels.push(label.clone());
+        }
+        if labels.is_empty() {
+            return self.text.clone();
+        }
+        format!("{}\n\n{}", labels.join("\n"), self.text)
+    }
+}
+
+/// Chunk metadata persisted as JSON lines under `.vs/`.
+pub struct ChunkStore {
+    path: PathBuf,
+    header: StoreHeader,
+    records: Vec<ChunkRecord>,
+    /// Position in `records` of each vector id.
+    by_id: HashMap<u64, usize>,
+    /// Positions in `records` of each file's chunks.
+    by_file: HashMap<String, Vec<usize>>,
+    next_id: u64,
+}
+
+impl ChunkStore {
+    /// Open the store in `vs_dir`. A missing store opens empty and is written on `save`.
+    pub fn open(vs_dir: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let path = vs_dir.join(STORE_FILE_NAME);
+        if !path.exists() {
+            return Ok(ChunkStore::from_records(path, StoreHeader::default(), Vec::new()));
+        }
+
+        let reader = BufReader::new(File::open(&path)?);
+        let mut lines = reader.lines();
+        let header: StoreHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => StoreHeader::default(),
+        };
+        if header.schema_version > SCHEMA_VERSION {
+            return Err(format!(
+                "{} uses schema version {}, this build of Fisher only understands up to {}",
+                path.display(),
+                header.schema_version,
+                SCHEMA_VERSION
+            ).into());
+        }
+
+        let mut records = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+        Ok(ChunkStore::from_records(path, header, records))
+    }
+
+    fn from_records(path: PathBuf, header: StoreHeader, records: Vec<ChunkRecord>) -> Self {
+        let mut store = ChunkStore {
+            path,
+            header,
+            records: Vec::new(),
+            by_id: HashMap::new(),
+            by_file: HashMap::new(),
+            next_id: 0,
+        };
+        store.insert(records);
+        store
+    }
+
+    /// FAISS factory string of the index these records belong to.
+    pub fn index_description(&self) -> &str {
+        &self.header.index_description
+    }
+
+    pub fn set_index_description(&mut self, description: &str) {
+        self.header.index_description = description.to_string();
+    }
+
+    /// Embedding model and vector length the index was built with, if recorded.
+    pub fn embedding(&self) -> Option<(&str, usize)> {
+        Some((self.header.embedding_model.as_deref()?, self.header.dimension?))
+    }
+
+    pub fn set_embedding(&mut self, model: &str, dimension: usize) {
+        self.header.embedding_model = Some(model.to_string());
+        self.header.dimension = Some(dimension);
+    }
+
+    /// All records. Removing a file moves later records into its place, so they are
+    /// not kept in the order they were added.
+    pub fn records(&self) -> &[ChunkRecord] {
+        &self.records
+    }
+
+    /// Look up the record for a vector id.
+    pub fn get(&self, id: u64) -> Option<&ChunkRecord> {
+        self.by_id.get(&id).map(|&i| &self.records[i])
+    }
+
+    /// Id to assign to the next vector added to the index.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Names of all files that have records, sorted.
+    pub fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = self.by_file.keys().cloned().collect();
+        files.sort();
+        files
+    }
+
+    /// Records belonging to `file`, in chunk order.
+    pub fn file_records(&self, file: &str) -> Vec<&ChunkRecord> {
+        let positions = self.by_file.get(file).map(Vec::as_slice).unwrap_or_default();
+        let mut records: Vec<&ChunkRecord> = positions.iter().map(|&i| &self.records[i]).collect();
+        records.sort_by_key(|r| r.chunk);
+        records
+    }
+
+    /// Add records in memory; call `save` to persist them.
+    pub fn insert(&mut self, records: Vec<ChunkRecord>) {
+        for record in records {
+            let position = self.records.len();
+            self.next_id = self.next_id.max(record.id + 1);
+            self.by_id.insert(record.id, position);
+            self.by_file.entry(record.file.clone()).or_default().push(position);
+            self.records.push(record);
+        }
+    }
+
+    /// Remove and return every record belonging to `file`, in chunk order.
+    pub fn remove_file(&mut self, file: &str) -> Vec<ChunkRecord> {
+        let mut positions = self.by_file.remove(file).unwrap_or_default();
+        // Going from the back, the record swapped into each hole belongs to another file
+        positions.sort_unstable_by(|a, b| b.cmp(a));
+        let mut removed = Vec::with_capacity(positions.len());
+        for position in positions {
+            let record = self.records.swap_remove(position);
+            self.by_id.remove(&record.id);
+            if let Some(moved) = self.records.get(position) {
+                let old = self.records.len();
+                self.by_id.insert(moved.id, position);
+                if let Some(slot) = self.by_file.get_mut(&moved.file).and_then(|p| p.iter_mut().find(|p| **p == old)) {
+                    *slot = position;
+                }
+            }
+            removed.push(record);
+        }
+        removed.sort_by_key(|r| r.chunk);
+        removed
+    }
+
+    /// Write the header and all records to disk, replacing the previous file.
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", serde_json::to_string(&self.header)?)?;
+        for record in &self.records {
+            writeln!(file, "{}", serde_json::to_string(record)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Embedding model and vector length recorded for the index in `vs_dir`, read
+/// from the header of its store without loading the records.
+pub fn read_embedding(vs_dir: &Path) -> Option<(String, usize)> {
+    let file = File::open(vs_dir.join(STORE_FILE_NAME)).ok()?;
+    let line = BufReader::new(file).lines().next()?.ok()?;
+    let header: StoreHeader = serde_json::from_str(&line).ok()?;
+    Some((header.embedding_model?, header.dimension?))
+}
+
+/// Read `faiss_lookup.txt` as `(file, chunk_count)` pairs if the directory still has one.
+pub fn read_legacy_lookup(vs_dir: &Path) -> Option<Vec<(String, usize)>> {
+    let file = File::open(vs_dir.join(LEGACY_LOOKUP_FILE_NAME)).ok()?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        // The old format separated path and count with a space, so split on the last one
+        let Some((name, rest)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        // Lines written with chunk ranges have a third field
+        let (name, count) = match name.rsplit_once(' ') {
+            Some((file_name, count)) if rest.contains('-') || rest.is_empty() => (file_name, count),
+            _ => (name, rest),
+        };
+        if let Ok(count) = count.parse::<usize>() {
+            entries.push((name.to_string(), count));
+        }
+    }
+    Some(entries)
+}
+
+/// Move `faiss_lookup.txt` aside once its contents live in the metadata store.
+pub fn retire_legacy_lookup(vs_dir: &Path) -> std::io::Result<()> {
+    let path = vs_dir.join(LEGACY_LOOKUP_FILE_NAME);
+    fs::rename(&path, path.with_extension("txt.migrated"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, file: &str, chunk: usize) -> ChunkRecord {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "file": file, "chunk": chunk, "text": "text", "start": 0, "end": 4,
+            "file_hash": "", "mtime": 0, "embedding_model": "hash-8",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn removing_a_file_keeps_the_others_reachable() {
+        let mut store = ChunkStore::open(Path::new("docs/.vs")).unwrap();
+        store.insert(vec![
+            record(0, "a.txt", 0),
+            record(1, "a.txt", 1),
+            record(2, "b.txt", 0),
+            record(3, "b.txt", 1),
+            record(4, "c.txt", 0),
+        ]);
+
+        let removed = store.remove_file("a.txt");
+        assert_eq!(removed.iter().map(|r| r.id).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(store.records().len(), 3);
+        assert!(store.get(0).is_none() && store.get(1).is_none());
+        for (id, file) in [(2, "b.txt"), (3, "b.txt"), (4, "c.txt")] {
+            assert_eq!(store.get(id).unwrap().file, file);
+        }
+        let b: Vec<u64> = store.file_records("b.txt").iter().map(|r| r.id).collect();
+        assert_eq!(b, [2, 3]);
+        assert_eq!(store.files(), ["b.txt", "c.txt"]);
+        // Ids are never reused, even after the records holding them are gone
+        assert_eq!(store.next_id(), 5);
+        assert!(store.remove_file("a.txt").is_empty());
+    }
+
+    #[test]
+    fn legacy_lookup_lines_with_and_without_ranges() {
+        let vs_dir = std::env::temp_dir().join(format!("fisher-lookup-{}", std::process::id()));
+        assert!(read_legacy_lookup(&vs_dir).is_none());
+
+        fs::create_dir_all(&vs_dir).unwrap();
+        let lines = [
+            "docs/old.txt 3",
+            "docs/old notes.txt 2",
+            "docs/new.txt 2 0-10,10-20",
+            "docs/new notes.md 1 0-5",
+            "docs/empty.txt 0 ",
+            "not a lookup line",
+        ];
+        fs::write(vs_dir.join(LEGACY_LOOKUP_FILE_NAME), lines.join("\n") + "\n").unwrap();
+        let entries = read_legacy_lookup(&vs_dir).unwrap();
+        fs::remove_dir_all(&vs_dir).unwrap();
+
+        let expected = [
+            ("docs/old.txt", 3),
+            ("docs/old notes.txt", 2),
+            ("docs/new.txt", 2),
+            ("docs/new notes.md", 1),
+            ("docs/empty.txt", 0),
+        ];
+        assert_eq!(entries, expected.map(|(file, count)| (file.to_string(), count)));
+    }
+}
@@ -1,14 +1,17 @@
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use crate::chat_interface::Message;
+use std::collections::VecDeque;
 use std::env;
 
 
 // Chat/LLM Structures
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Part {
-    text: String,
+pub(crate) struct Part {
+    pub(crate) text: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,169 +21,429 @@ struct ContentWithRole {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Content {
-    parts: Vec<Part>,
+pub(crate) struct Content {
+    pub(crate) parts: Vec<Part>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct GenerateContentRequest {
+    #[serde(rename = "systemInstruction", skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content>,
     contents: Vec<ContentWithRole>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GenerateContentResponse {
-    candidates: Option<Vec<Candidate>>,
+/// System instruction used when the question is grounded in retrieved passages.
+const GROUNDED_SYSTEM_INSTRUCTION: &str = "You are Fisher, an assistant that answers questions about the user's documents. \
+Answer only from the numbered passages supplied with the latest question, \
+citing the passages you use with their numbers in square brackets, e.g. [2]. \
+Each passage starts with the file it comes from and, when known, its lines, e.g. src/main.rs:10-42; \
+when asked where something is, give that location. \
+If the passages do not contain the answer, say that the documents do not cover it instead of guessing.";
+
+/// Prefix a question with the numbered passages retrieved for it.
+fn ground_question(question: &str, context: &[String]) -> String {
+    let mut prompt = String::from("Passages:\n");
+    for (i, chunk) in context.iter().enumerate() {
+        prompt.push_str(&format!("[{}] {}\n\n", i + 1, chunk));
+    }
+    prompt.push_str("Question: ");
+    prompt.push_str(question);
+    prompt
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Candidate {
-    content: ContentWithRole,
-    finish_reason: Option<String>,
+/// Speaker of a turn in the conversation sent to a chat model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    User,
+    Assistant,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SingleEmbeddingRequest {
-    model: String,
-    content: Content,
-    #[serde(rename = "taskType")]
-    task_type: String,
+/// Turn the chat history into alternating turns, grounding the latest user turn in `context`.
+///
+/// Notices from Fisher itself and replies that failed or were stopped are dropped,
+/// and consecutive turns from the same speaker are merged, since several APIs
+/// reject two user turns in a row.
+fn conversation(messages: &[Message], context: &[String]) -> Vec<(Role, String)> {
+    let messages: Vec<&Message> = messages
+        .iter()
+        .filter(|msg| (msg.sender == "User" || msg.sender == "LLM") && !msg.incomplete)
+        .collect();
+    let last = messages.len().saturating_sub(1);
+
+    let mut turns: Vec<(Role, String)> = Vec::new();
+    for (i, msg) in messages.iter().enumerate() {
+        let role = if msg.sender == "User" { Role::User } else { Role::Assistant };
+        let text = if i == last && role == Role::User {
+            ground_question(&msg.content, context)
+        } else {
+            msg.content.clone()
+        };
+        match turns.last_mut() {
+            Some((last_role, last_text)) if *last_role == role => {
+                last_text.push_str("\n\n");
+                last_text.push_str(&text);
+            }
+            _ => turns.push((role, text)),
+        }
+    }
+    turns
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BatchEmbeddingRequest {
-    requests: Vec<SingleEmbeddingRequest>,
+/// Text of a reply as it is produced, one piece at a time.
+pub type TokenStream = BoxStream<'static, Result<String, Box<dyn std::error::Error + Send + Sync>>>;
+
+/// A language model that answers questions about the retrieved passages.
+#[async_trait]
+pub trait ChatModel: Send + Sync {
+    /// Name of the provider and model, for display.
+    fn name(&self) -> String;
+
+    /// Generate a reply to the conversation, grounding the latest user turn in `context`.
+    async fn generate_response(&self, messages: &[Message], context: &[String]) -> Result<String, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Like `generate_response`, but yield the reply as it is generated.
+    /// Dropping the stream abandons the request.
+    async fn stream_response(&self, messages: &[Message], context: &[String]) -> Result<TokenStream, Box<dyn std::error::Error + Send + Sync>>;
 }
 
-fn sender_to_role(sender: &str) -> &str {
-    match sender {
-        "User" => "user",
-        "LLM" => "model",
-        _ => "user", // fallback
+/// Build the chat model selected by `FISHER_CHAT_PROVIDER` (`gemini`, `openai` or `anthropic`).
+///
+/// `FISHER_CHAT_MODEL` picks the model, `FISHER_CHAT_URL` overrides the server and
+/// `FISHER_CHAT_API_KEY` the key, which otherwise comes from the provider's usual variable.
+pub fn chat_model_from_env() -> Result<Box<dyn ChatModel>, Box<dyn std::error::Error + Send + Sync>> {
+    let provider = env::var("FISHER_CHAT_PROVIDER").unwrap_or_else(|_| "gemini".to_string());
+    let model = env::var("FISHER_CHAT_MODEL").ok();
+    let url = env::var("FISHER_CHAT_URL").ok();
+    let api_key = |fallback: &str| env::var("FISHER_CHAT_API_KEY").or_else(|_| env::var(fallback)).ok();
+
+    match provider.as_str() {
+        "gemini" => Ok(Box::new(GeminiChat {
+            client: Client::new(),
+            base_url: url.unwrap_or_else(|| "https://generativelanguage.googleapis.com".to_string()),
+            model: model.unwrap_or_else(|| "gemini-2.5-flash".to_string()),
+            api_key: api_key("GEMINI_API_KEY").ok_or("GEMINI_API_KEY not set in environment")?,
+        })),
+        "openai" => Ok(Box::new(OpenAiChat {
+            client: Client::new(),
+            base_url: url.unwrap_or_else(|| "http://localhost:8080".to_string()),
+            model: model.ok_or("FISHER_CHAT_MODEL not set in environment")?,
+            api_key: api_key("OPENAI_API_KEY"),
+        })),
+        "anthropic" => Ok(Box::new(AnthropicChat {
+            client: Client::new(),
+            base_url: url.unwrap_or_else(|| "https://api.anthropic.com".to_string()),
+            model: model.ok_or("FISHER_CHAT_MODEL not set in environment")?,
+            api_key: api_key("ANTHROPIC_API_KEY").ok_or("ANTHROPIC_API_KEY not set in environment")?,
+        })),
+        other => Err(format!("Unknown chat provider '{}'", other).into()),
     }
 }
-pub async fn generate_response(messages: &[Message]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-
-    let client = Client::new();
-    let api_key = env::var("GEMINI_API_KEY")
-        .map_err(|_| "GEMINI_API_KEY not set in environment")?;
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash:generateContent?key={}",
-        api_key
-    );
 
-    let contents: Vec<ContentWithRole> = messages.iter().map(|msg| ContentWithRole {
-        role: sender_to_role(&msg.sender).to_string(),
-        parts: vec![Part { text: msg.content.clone() }],
-    }).collect();
+// Gemini
 
-    let request_body = GenerateContentRequest { contents };
+/// Gemini models through the Generative Language API.
+pub struct GeminiChat {
+    client: Client,
+    base_url: String,
+    model: String,
+    api_key: String,
+}
 
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await?;
+impl GeminiChat {
+    fn request_body(messages: &[Message], context: &[String]) -> GenerateContentRequest {
+        let contents: Vec<ContentWithRole> = conversation(messages, context)
+            .into_iter()
+            .map(|(role, text)| ContentWithRole {
+                role: match role {
+                    Role::User => "user",
+                    Role::Assistant => "model",
+                }.to_string(),
+                parts: vec![Part { text }],
+            })
+            .collect();
 
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("API request failed: {}", error_text).into());
+        GenerateContentRequest {
+            system_instruction: Some(Content {
+                parts: vec![Part { text: GROUNDED_SYSTEM_INSTRUCTION.to_string() }],
+            }),
+            contents,
+        }
     }
 
-    let response_body: GenerateContentResponse = response.json().await?;
-    if let Some(candidates) = response_body.candidates {
-        if let Some(candidate) = candidates.first() {
-            if let Some(part) = candidate.content.parts.first() {
-                return Ok(part.text.clone());
-            }
+    async fn send(&self, method: &str, body: &GenerateContentRequest) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        let separator = if method.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}/v1beta/models/{}:{}{}key={}",
+            self.base_url.trim_end_matches('/'), self.model, method, separator, self.api_key
+        );
+        let response = self.client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .json(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("API request failed: {}", error_text).into());
         }
+        Ok(response)
     }
-    Err("No response generated".into())
-}
-
-pub async fn generate_embedding_document(texts: &[String]) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error + Send + Sync>> {
-    let client = Client::new();
-    let api_key = env::var("GEMINI_API_KEY")
-        .map_err(|_| "GEMINI_API_KEY not set in environment")?;
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-embedding-001:batchEmbedContents?key={}",
-        api_key
-    );
-
-    let requests: Vec<SingleEmbeddingRequest> = texts.iter().map(|t| SingleEmbeddingRequest {
-        model: "models/gemini-embedding-001".to_string(),
-        content: Content {
-            parts: vec![Part { text: t.clone() }],
-        },
-        task_type: "RETRIEVAL_DOCUMENT".to_string(),
-    }).collect();
-
-    let request_body = BatchEmbeddingRequest { requests };
-
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await?;
-
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("API request failed: {}", error_text).into());
-    }
-
-    let response_body: serde_json::Value = response.json().await?;
-    let mut embeddings = Vec::new();
-    if let Some(arr) = response_body.get("embeddings").and_then(|v| v.as_array()) {
-        for emb in arr {
-            if let Some(values) = emb.get("values").and_then(|v| v.as_array()) {
-                let vec: Vec<f32> = values.iter().filter_map(|x| x.as_f64().map(|f| f as f32)).collect();
-                embeddings.push(vec);
-            }
+}
+
+/// Concatenate the text parts of the first candidate in a Gemini response.
+fn gemini_text(body: &serde_json::Value) -> Option<String> {
+    let parts = body.pointer("/candidates/0/content/parts")?.as_array()?;
+    Some(parts.iter().filter_map(|p| p.get("text").and_then(|t| t.as_str())).collect())
+}
+
+#[async_trait]
+impl ChatModel for GeminiChat {
+    fn name(&self) -> String {
+        format!("gemini/{}", self.model)
+    }
+
+    async fn generate_response(&self, messages: &[Message], context: &[String]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send("generateContent", &Self::request_body(messages, context)).await?;
+
+        // Read every text part, as the streaming path does for each event
+        let response_body: serde_json::Value = response.json().await?;
+        gemini_text(&response_body).ok_or_else(|| "No response generated".into())
+    }
+
+    async fn stream_response(&self, messages: &[Message], context: &[String]) -> Result<TokenStream, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send("streamGenerateContent?alt=sse", &Self::request_body(messages, context)).await?;
+        Ok(sse_text_stream(response, |event| Ok(gemini_text(event))))
+    }
+}
+
+// OpenAI-compatible servers (llama.cpp, vLLM, Ollama, ...)
+
+/// Any server exposing OpenAI's `/v1/chat/completions` endpoint.
+pub struct OpenAiChat {
+    client: Client,
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiChat {
+    async fn send(&self, messages: &[Message], context: &[String], stream: bool) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        let mut chat_messages = vec![serde_json::json!({
+            "role": "system",
+            "content": GROUNDED_SYSTEM_INSTRUCTION,
+        })];
+        for (role, text) in conversation(messages, context) {
+            chat_messages.push(serde_json::json!({
+                "role": match role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                },
+                "content": text,
+            }));
+        }
+
+        let mut request = self.client
+            .post(format!("{}/v1/chat/completions", self.base_url.trim_end_matches('/')))
+            .json(&serde_json::json!({
+                "model": self.model,
+                "messages": chat_messages,
+                "stream": stream,
+            }));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("API request failed: {}", error_text).into());
         }
+        Ok(response)
     }
-    Ok(embeddings)
-}
-
-pub async fn generate_embedding_query(query: &str) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
-    let client = Client::new();
-    let api_key = env::var("GEMINI_API_KEY")
-        .map_err(|_| "GEMINI_API_KEY not set in environment")?;
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-embedding-001:embedContent?key={}",
-        api_key
-    );
-
-    let request_body = serde_json::json!({
-        "model": "models/gemini-embedding-001",
-        "content": {
-            "parts": [
-                {"text": query}
-            ]
-        },
-        "taskType": "RETRIEVAL_QUERY"
-    });
+}
 
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await?;
+#[async_trait]
+impl ChatModel for OpenAiChat {
+    fn name(&self) -> String {
+        format!("openai/{}", self.model)
+    }
 
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("API request failed: {}", error_text).into());
+    async fn generate_response(&self, messages: &[Message], context: &[String]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send(messages, context, false).await?;
+
+        let response_body: serde_json::Value = response.json().await?;
+        response_body
+            .pointer("/choices/0/message/content")
+            .and_then(|v| v.as_str())
+            .map(|text| text.to_string())
+            .ok_or_else(|| "No response generated".into())
     }
 
-    let response_body: serde_json::Value = response.json().await?;
-    if let Some(embedding) = response_body.get("embedding") {
-        if let Some(values) = embedding.get("values").and_then(|v| v.as_array()) {
-            let embedding: Vec<f32> = values.iter().filter_map(|x| x.as_f64().map(|f| f as f32)).collect();
-            return Ok(embedding);
+    async fn stream_response(&self, messages: &[Message], context: &[String]) -> Result<TokenStream, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send(messages, context, true).await?;
+        Ok(sse_text_stream(response, |event| {
+            Ok(event
+                .pointer("/choices/0/delta/content")
+                .and_then(|v| v.as_str())
+                .map(|text| text.to_string()))
+        }))
+    }
+}
+
+// Anthropic
+
+/// Anthropic-style `/v1/messages` APIs.
+pub struct AnthropicChat {
+    client: Client,
+    base_url: String,
+    model: String,
+    api_key: String,
+}
+
+impl AnthropicChat {
+    async fn send(&self, messages: &[Message], context: &[String], stream: bool) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        let chat_messages: Vec<serde_json::Value> = conversation(messages, context)
+            .into_iter()
+            .map(|(role, text)| serde_json::json!({
+                "role": match role {
+                    Role::User => "user",
+                    Role::Assistant => "assistant",
+                },
+                "content": text,
+            }))
+            .collect();
+
+        let response = self.client
+            .post(format!("{}/v1/messages", self.base_url.trim_end_matches('/')))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .json(&serde_json::json!({
+                "model": self.model,
+                "max_tokens": 4096,
+                "system": GROUNDED_SYSTEM_INSTRUCTION,
+                "messages": chat_messages,
+                "stream": stream,
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("API request failed: {}", error_text).into());
         }
+        Ok(response)
     }
-    Err("No embedding generated".into())
 }
 
+#[async_trait]
+impl ChatModel for AnthropicChat {
+    fn name(&self) -> String {
+        format!("anthropic/{}", self.model)
+    }
 
+    async fn generate_response(&self, messages: &[Message], context: &[String]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send(messages, context, false).await?;
+
+        let response_body: serde_json::Value = response.json().await?;
+        let text: String = response_body
+            .get("content")
+            .and_then(|v| v.as_array())
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .filter(|b| b.get("type").and_then(|t| t.as_str()) == Some("text"))
+                    .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if text.is_empty() {
+            return Err("No response generated".into());
+        }
+        Ok(text)
+    }
+
+    async fn stream_response(&self, messages: &[Message], context: &[String]) -> Result<TokenStream, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.send(messages, context, true).await?;
+        Ok(sse_text_stream(response, |event| {
+            match event.get("type").and_then(|t| t.as_str()) {
+                Some("content_block_delta") => Ok(event
+                    .pointer("/delta/text")
+                    .and_then(|v| v.as_str())
+                    .map(|text| text.to_string())),
+                Some("error") => Err(event
+                    .pointer("/error/message")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("Stream failed")
+                    .to_string()),
+                _ => Ok(None),
+            }
+        }))
+    }
+}
+
+// Server-sent events
+
+/// Turn a server-sent events response into a stream of text pieces.
+///
+/// Each `data:` line is parsed as JSON and handed to `extract`, which returns the
+/// text it carries (if any) or an error reported by the server.
+fn sse_text_stream(
+    response: reqwest::Response,
+    extract: fn(&serde_json::Value) -> Result<Option<String>, String>,
+) -> TokenStream {
+    let bytes = Box::pin(response.bytes_stream());
+    let state = (bytes, Vec::<u8>::new(), VecDeque::new());
+    let stream = stream::unfold(state, move |(mut bytes, mut buffer, mut pending)| async move {
+        loop {
+            if let Some(item) = pending.pop_front() {
+                return Some((item, (bytes, buffer, pending)));
+            }
+            match bytes.next().await {
+                Some(Ok(data)) => {
+                    buffer.extend_from_slice(&data);
+                    // Only complete lines are parsed; the rest waits for more bytes
+                    while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=pos).collect();
+                        let line = String::from_utf8_lossy(&line);
+                        let Some(data) = line.trim().strip_prefix("data:") else {
+                            continue;
+                        };
+                        let data = data.trim();
+                        if data.is_empty() || data == "[DONE]" {
+                            continue;
+                        }
+                        let item: Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> =
+                            match serde_json::from_str(data) {
+                                Ok(event) => extract(&event).map_err(|e| e.into()),
+                                Err(e) => Err(e.into()),
+                            };
+                        match item {
+                            Ok(Some(text)) if !text.is_empty() => pending.push_back(Ok(text)),
+                            Ok(_) => {}
+                            Err(e) => pending.push_back(Err(e)),
+                        }
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e.into()), (bytes, buffer, pending))),
+                None => return None,
+            }
+        }
+    });
+    Box::pin(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gemini_text_joins_every_part_of_the_first_candidate() {
+        let body = serde_json::json!({"candidates": [
+            {"content": {"role": "model", "parts": [{"text": "Fisher reads "}, {"text": "PDFs [1]."}]}},
+            {"content": {"role": "model", "parts": [{"text": "ignored"}]}},
+        ]});
+        assert_eq!(gemini_text(&body).as_deref(), Some("Fisher reads PDFs [1]."));
+        assert_eq!(gemini_text(&serde_json::json!({"promptFeedback": {}})), None);
+    }
+}
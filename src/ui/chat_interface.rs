@@ -5,12 +5,21 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph, Wrap, Padding},
     Frame,
 };
+use crate::files::Source;
+
+/// Frames of the spinner shown while background work is running.
+const SPINNER_FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
 
 /// Represents a single chat message.
 #[derive(Debug, Clone)]
 pub struct Message {
     pub sender: String,
     pub content: String,
+    /// Chunks the message was grounded in, rendered as numbered footnotes.
+    pub sources: Vec<Source>,
+    /// Whether the reply failed or was stopped, which keeps it out of the history
+    /// sent to the chat model.
+    pub incomplete: bool,
 }
 
 /// Manages the chat interface state and rendering.
@@ -20,6 +29,16 @@ pub struct ChatInterface {
     pub input_cursor_position: usize,
     pub scroll_offset: usize,
     pub scroll_to_bottom: bool,
+    /// Whether the end of the conversation was in view when last drawn. Streamed
+    /// text only keeps the view at the bottom if it already was.
+    pinned: bool,
+    /// Name of the chat model, shown in the title bar.
+    pub model_name: String,
+    /// Whether a reply is currently being streamed.
+    pub streaming: bool,
+    /// Background work to show with a spinner in the title bar, e.g. "indexing".
+    pub status: Option<String>,
+    spinner_frame: usize,
 }
 
 impl ChatInterface {
@@ -31,34 +50,88 @@ impl ChatInterface {
             input_cursor_position: 0,
             scroll_offset: 0,
             scroll_to_bottom: false,
+            pinned: true,
+            model_name: String::new(),
+            streaming: false,
+            status: None,
+            spinner_frame: 0,
         }
     }
 
     /// Add a message to the chat history.
     pub fn add_message(&mut self, sender: &str, content: &str) {
+        self.add_message_with_sources(sender, content, Vec::new());
+    }
+
+    /// Add a message along with the sources it cites.
+    pub fn add_message_with_sources(&mut self, sender: &str, content: &str, sources: Vec<Source>) {
         self.messages.push(Message {
             sender: sender.to_string(),
             content: content.to_string(),
+            sources,
+            incomplete: false,
         });
         self.scroll_to_bottom = true;
     }
 
+    /// Add a notice from Fisher itself; notices are not sent to the chat model.
+    pub fn add_notice(&mut self, content: &str) {
+        self.add_message("Fisher", content);
+    }
+
+    /// Append streamed text to the message at `index`.
+    pub fn append_to_message(&mut self, index: usize, text: &str) {
+        if let Some(message) = self.messages.get_mut(index) {
+            message.content.push_str(text);
+        }
+        self.follow_updates();
+    }
+
+    /// Replace the text of the message at `index`.
+    pub fn set_message_content(&mut self, index: usize, content: &str) {
+        if let Some(message) = self.messages.get_mut(index) {
+            message.content = content.to_string();
+        }
+        self.follow_updates();
+    }
+
+    /// Mark the message at `index` as a reply that failed or was stopped.
+    pub fn set_message_incomplete(&mut self, index: usize) {
+        if let Some(message) = self.messages.get_mut(index) {
+            message.incomplete = true;
+        }
+    }
+
+    /// Attach sources to the message at `index`.
+    pub fn set_message_sources(&mut self, index: usize, sources: Vec<Source>) {
+        if let Some(message) = self.messages.get_mut(index) {
+            message.sources = sources;
+        }
+        self.follow_updates();
+    }
+
+    /// Scroll to the bottom after a message changed, unless the user scrolled away from it.
+    fn follow_updates(&mut self) {
+        self.scroll_to_bottom |= self.pinned;
+    }
+
+    /// Advance the spinner shown next to the status.
+    pub fn tick(&mut self) {
+        self.spinner_frame = (self.spinner_frame + 1) % SPINNER_FRAMES.len();
+    }
+
     /// Handle user input (character, backspace, enter, etc).
     pub fn handle_input(&mut self, key: char) {
         match key {
-            '\n' => {
-                if !self.input.trim().is_empty() {
-                    let input = std::mem::take(&mut self.input);
-                    self.add_message("User", &input);
-                    self.input_cursor_position = 0;
-                }
+            '\n' if !self.input.trim().is_empty() => {
+                let input = std::mem::take(&mut self.input);
+                self.add_message("User", &input);
+                self.input_cursor_position = 0;
             }
-            '\x08' | '\x7f' => {
+            '\x08' | '\x7f' if self.input_cursor_position > 0 => {
                 // Backspace
-                if self.input_cursor_position > 0 {
-                    self.input.remove(self.input_cursor_position - 1);
-                    self.input_cursor_position -= 1;
-                }
+                self.input.remove(self.input_cursor_position - 1);
+                self.input_cursor_position -= 1;
             }
             '\x1b' => {
                 // Escape key - clear input
@@ -84,6 +157,8 @@ impl ChatInterface {
         if self.scroll_offset > 0 {
             self.scroll_offset = self.scroll_offset.saturating_sub(1);
         }
+        self.pinned = false;
+        self.scroll_to_bottom = false;
     }
     pub fn scroll_down(&mut self) {
         self.scroll_offset = self.scroll_offset.saturating_add(1);
@@ -122,45 +197,24 @@ impl ChatInterface {
 
             // Split content into lines that fit the width
             let max_width = area.width.saturating_sub(4) as usize; // Account for borders
-            let words: Vec<&str> = msg.content.split_whitespace().collect();
-            let mut current_line = String::new();
-            for word in words {
-                if word.len() > max_width {
-                    // Break the word into chunks of max_width
-                    for chunk in word.as_bytes().chunks(max_width) {
-                        if !current_line.is_empty() {
-                            conversation_text.push(Line::from(vec![
-                                Span::styled(current_line.clone(), content_style),
-                            ]));
-                            current_line.clear();
-                        }
-                        let chunk_str = String::from_utf8_lossy(chunk).to_string();
-                        conversation_text.push(Line::from(vec![
-                            Span::styled(chunk_str, content_style),
-                        ]));
-                    }
-                } else if current_line.len() + word.len() < max_width {
-                    if !current_line.is_empty() {
-                        current_line.push(' ');
-                    }
-                    current_line.push_str(word);
-                } else {
-                    // Start a new line
-                    if !current_line.is_empty() {
+            for line in wrap_words(&msg.content, max_width) {
+                conversation_text.push(Line::from(vec![
+                    Span::styled(line, content_style),
+                ]));
+            }
+
+            // Add numbered footnotes for the cited sources
+            if !msg.sources.is_empty() {
+                let source_style = Style::default().fg(Color::Rgb(0x8A, 0x8A, 0x8A));
+                conversation_text.push(Line::from(""));
+                for (i, source) in msg.sources.iter().enumerate() {
+                    for line in wrap_words(&format_source(i + 1, source), max_width) {
                         conversation_text.push(Line::from(vec![
-                            Span::styled(current_line.clone(), content_style),
+                            Span::styled(line, source_style),
                         ]));
-                        current_line.clear();
                     }
-                    current_line.push_str(word);
                 }
             }
-            // Add the last line if not empty
-            if !current_line.is_empty() {
-                conversation_text.push(Line::from(vec![
-                    Span::styled(current_line, content_style),
-                ]));
-            }
             // Add a blank line between messages
             conversation_text.push(Line::from(""));
         }
@@ -188,6 +242,7 @@ impl ChatInterface {
         };
 
         let end_index = (start_index + available_height).min(total_lines);
+        self.pinned = end_index == total_lines;
 
         let visible_text: Vec<Line> = conversation_text
             .into_iter()
@@ -197,9 +252,17 @@ impl ChatInterface {
 
         let text = Text::from(visible_text);
 
+        let mut title = String::from(" Fisher ");
+        if !self.model_name.is_empty() {
+            title.push_str(&format!("· {} ", self.model_name));
+        }
+        if let Some(status) = &self.status {
+            title.push_str(&format!("{} {}… ", SPINNER_FRAMES[self.spinner_frame], status));
+        }
+        title.push_str(if self.streaming { "[\"esc\" to stop] " } else { "[\"esc\" to quit] " });
         let paragraph = Paragraph::new(text)
             .block(Block::default()
-                .title(" Fisher [\"esc\" to quit] ")
+                .title(title)
                 .borders(Borders::ALL)
                 .border_style(Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54)))
                 .padding(Padding { left: 1, right: 1, top: 0, bottom: 0 })
@@ -235,10 +298,52 @@ impl ChatInterface {
         frame.set_cursor_position((cursor_x, cursor_y));
     }
 
-    /// Get the last message in the chat history.
-    pub fn get_last_message(&self) -> Option<&Message> {
-        self.messages.last()
+    /// The latest user message if it has not been answered yet, skipping notices.
+    pub fn pending_question(&self) -> Option<&Message> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|m| m.sender != "Fisher")
+            .filter(|m| m.sender == "User")
+    }
+}
+
+/// Split text into lines no wider than `max_width`, breaking overlong words.
+fn wrap_words(text: &str, max_width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current_line = String::new();
+    for word in text.split_whitespace() {
+        if word.len() > max_width {
+            // Break the word into chunks of max_width
+            for chunk in word.as_bytes().chunks(max_width.max(1)) {
+                if !current_line.is_empty() {
+                    lines.push(std::mem::take(&mut current_line));
+                }
+                lines.push(String::from_utf8_lossy(chunk).to_string());
+            }
+        } else if current_line.len() + word.len() < max_width {
+            if !current_line.is_empty() {
+                current_line.push(' ');
+            }
+            current_line.push_str(word);
+        } else {
+            // Start a new line
+            if !current_line.is_empty() {
+                lines.push(std::mem::take(&mut current_line));
+            }
+            current_line.push_str(word);
+        }
     }
+    // Add the last line if not empty
+    if !current_line.is_empty() {
+        lines.push(current_line);
+    }
+    lines
+}
+
+/// Format a source as a footnote, e.g. `[1] notes.txt (chunk 2, bytes 1000-1980) score 0.81`.
+fn format_source(number: usize, source: &Source) -> String {
+    format!("[{}] {}", number, source)
 }
 
 impl Default for ChatInterface {
@@ -0,0 +1,356 @@
+use ratatui::{
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style, Modifier},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, Gauge, Paragraph, Padding},
+    Frame,
+};
+use std::time::{Duration, Instant};
+use crate::files::IndexProgress;
+
+/// Frames of the spinner shown while indexing is running.
+const SPINNER_FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Where an indexing run is at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexingPhase {
+    Running,
+    /// Cancel was requested; waiting for the current file to finish.
+    Cancelling,
+    Finished,
+    Failed(String),
+}
+
+/// One file's line in the progress list.
+struct FileLine {
+    path: String,
+    chunks: usize,
+    embedded: usize,
+}
+
+/// Manages the indexing progress screen state and rendering.
+pub struct IndexingScreen {
+    pub directory: String,
+    pub phase: IndexingPhase,
+    files_discovered: Option<usize>,
+    files_done: usize,
+    /// Stored files given their vectors again, out of how many, while the index is rebuilt.
+    rebuild: Option<(usize, usize)>,
+    files_embedded: usize,
+    chunks_embedded: usize,
+    chunks_cached: usize,
+    api_calls: usize,
+    skipped: Vec<String>,
+    errors: Vec<String>,
+    files: Vec<FileLine>,
+    started: Instant,
+    spinner_frame: usize,
+}
+
+impl IndexingScreen {
+    /// Create a new indexing screen for `directory`.
+    pub fn new(directory: &str) -> Self {
+        Self {
+            directory: directory.to_string(),
+            phase: IndexingPhase::Running,
+            files_discovered: None,
+            files_done: 0,
+            rebuild: None,
+            files_embedded: 0,
+            chunks_embedded: 0,
+            chunks_cached: 0,
+            api_calls: 0,
+            skipped: Vec::new(),
+            errors: Vec::new(),
+            files: Vec::new(),
+            started: Instant::now(),
+            spinner_frame: 0,
+        }
+    }
+
+    /// Record a progress event from the indexer.
+    pub fn apply(&mut self, progress: IndexProgress) {
+        match progress {
+            IndexProgress::Scanned { files, skipped } => {
+                self.files_discovered = Some(files);
+                self.skipped = skipped
+                    .into_iter()
+                    .map(|s| format!("{} ({})", s.path.display(), s.reason))
+                    .collect();
+            }
+            IndexProgress::FileIndexed { path, chunks, embedded, cached, api_calls } => {
+                self.files_done += 1;
+                self.api_calls += api_calls;
+                self.chunks_cached += cached;
+                if embedded > 0 {
+                    self.files_embedded += 1;
+                    self.chunks_embedded += embedded;
+                }
+                self.files.push(FileLine {
+                    path: path.display().to_string(),
+                    chunks,
+                    embedded,
+                });
+            }
+            IndexProgress::FileFailed { path, error } => {
+                self.files_done += 1;
+                self.errors.push(format!("{}: {}", path.display(), error));
+            }
+            IndexProgress::Rebuilding { files_done, files, embedded, cached, api_calls } => {
+                self.rebuild = Some((files_done, files));
+                self.api_calls += api_calls;
+                self.chunks_cached += cached;
+                self.chunks_embedded += embedded;
+            }
+        }
+    }
+
+    /// Record the end of the run.
+    pub fn finish(&mut self, result: Result<(), String>) {
+        self.phase = match result {
+            Ok(()) => IndexingPhase::Finished,
+            Err(e) => IndexingPhase::Failed(e),
+        };
+    }
+
+    /// Whether the finished run has anything the user should look at before chatting.
+    pub fn has_problems(&self) -> bool {
+        !self.errors.is_empty() || !self.skipped.is_empty()
+    }
+
+    /// Advance the spinner.
+    pub fn tick(&mut self) {
+        self.spinner_frame = (self.spinner_frame + 1) % SPINNER_FRAMES.len();
+    }
+
+    /// Estimated time left, extrapolated from the files done so far.
+    fn eta(&self) -> Option<Duration> {
+        let total = self.files_discovered?;
+        if self.files_done == 0 || self.files_done >= total {
+            return None;
+        }
+        let per_file = self.started.elapsed().as_secs_f64() / self.files_done as f64;
+        Some(Duration::from_secs_f64(per_file * (total - self.files_done) as f64))
+    }
+
+    /// Handle user input for the indexing screen.
+    pub fn handle_input(&mut self, key: char) -> IndexingAction {
+        match (&self.phase, key) {
+            (IndexingPhase::Running, 'c' | 'C' | '\x1b') => {
+                self.phase = IndexingPhase::Cancelling;
+                IndexingAction::Cancel
+            }
+            (IndexingPhase::Finished, '\n' | 'c' | 'C') => IndexingAction::StartChat,
+            (IndexingPhase::Finished, '\x1b') => IndexingAction::Back,
+            (IndexingPhase::Failed(_), '\n' | '\x1b') => IndexingAction::Back,
+            _ => IndexingAction::Continue,
+        }
+    }
+
+    /// Render the indexing screen.
+    pub fn render(&mut self, frame: &mut Frame) {
+        // Horizontal layout for side padding
+        let h_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Length(4), // Left padding
+                Constraint::Min(0),   // Main content
+                Constraint::Length(4), // Right padding
+            ])
+            .split(frame.area());
+
+        self.render_padding(frame, h_chunks[0]);
+        self.render_padding(frame, h_chunks[2]);
+
+        let v_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(1),  // Top padding
+                Constraint::Length(1),  // Title area
+                Constraint::Length(3),  // Progress bar
+                Constraint::Length(12), // Statistics
+                Constraint::Min(3),     // Files and errors
+                Constraint::Length(1),  // Instructions
+            ])
+            .split(h_chunks[1]);
+
+        self.render_padding(frame, v_chunks[0]);
+        self.render_title(frame, v_chunks[1]);
+        self.render_progress(frame, v_chunks[2]);
+        self.render_statistics(frame, v_chunks[3]);
+        self.render_files(frame, v_chunks[4]);
+        self.render_instructions(frame, v_chunks[5]);
+    }
+
+    /// Render the title with a spinner while work is ongoing.
+    fn render_title(&self, frame: &mut Frame, area: Rect) {
+        let title_style = Style::default()
+            .fg(Color::Rgb(0xFD, 0x5F, 0x54))
+            .add_modifier(Modifier::BOLD);
+
+        let status = match &self.phase {
+            IndexingPhase::Running => format!("{} Indexing {}", SPINNER_FRAMES[self.spinner_frame], self.directory),
+            IndexingPhase::Cancelling => format!("{} Cancelling after the current file", SPINNER_FRAMES[self.spinner_frame]),
+            IndexingPhase::Finished => format!("Indexed {}", self.directory),
+            IndexingPhase::Failed(_) => "Indexing failed".to_string(),
+        };
+
+        let paragraph = Paragraph::new(Line::from(vec![Span::styled(status, title_style)]))
+            .alignment(ratatui::layout::Alignment::Center)
+            .style(Style::default().bg(Color::Rgb(0x0D, 0x0C, 0x11)));
+
+        frame.render_widget(paragraph, area);
+    }
+
+    /// Render the progress bar over discovered files, or over stored files while the
+    /// index is rebuilt.
+    fn render_progress(&self, frame: &mut Frame, area: Rect) {
+        let (ratio, label) = match (self.files_discovered, self.rebuild) {
+            (_, Some((done, total))) if done < total => (
+                done as f64 / total as f64,
+                format!("rebuilding index: {}/{} files", done, total),
+            ),
+            (Some(0), _) => (1.0, "no files".to_string()),
+            (Some(total), _) => (
+                (self.files_done as f64 / total as f64).min(1.0),
+                format!("{}/{} files", self.files_done, total),
+            ),
+            (None, _) => (0.0, "scanning".to_string()),
+        };
+
+        let gauge = Gauge::default()
+            .block(Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54)))
+            )
+            .gauge_style(Style::default()
+                .fg(Color::Rgb(0xFD, 0x5F, 0x54))
+                .bg(Color::Rgb(0x44, 0x17, 0x1C)))
+            .ratio(ratio)
+            .label(label);
+
+        frame.render_widget(gauge, area);
+    }
+
+    /// Render the counters.
+    fn render_statistics(&self, frame: &mut Frame, area: Rect) {
+        let label_style = Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54));
+        let value_style = Style::default().fg(Color::Rgb(0xFF, 0xD6, 0x00));
+
+        let elapsed = self.started.elapsed().as_secs();
+        let eta = match (&self.phase, self.eta()) {
+            (IndexingPhase::Running, Some(eta)) => format!("{}s", eta.as_secs()),
+            (IndexingPhase::Running, None) => "estimating".to_string(),
+            _ => "-".to_string(),
+        };
+        let rows = [
+            ("Files discovered", self.files_discovered.map(|n| n.to_string()).unwrap_or_else(|| "...".to_string())),
+            ("Index rebuild", self.rebuild.map(|(done, total)| format!("{}/{} files", done, total)).unwrap_or_else(|| "-".to_string())),
+            ("Files embedded", self.files_embedded.to_string()),
+            ("Chunks embedded", self.chunks_embedded.to_string()),
+            ("From cache", self.chunks_cached.to_string()),
+            ("API calls", self.api_calls.to_string()),
+            ("Skipped", self.skipped.len().to_string()),
+            ("Errors", self.errors.len().to_string()),
+            ("Elapsed", format!("{}s", elapsed)),
+            ("ETA", eta),
+        ];
+
+        let lines: Vec<Line> = rows
+            .into_iter()
+            .map(|(label, value)| Line::from(vec![
+                Span::styled(format!("{:<18}", label), label_style),
+                Span::styled(value, value_style),
+            ]))
+            .collect();
+
+        let paragraph = Paragraph::new(Text::from(lines))
+            .block(Block::default()
+                .title(" Progress ")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54)))
+                .padding(Padding { left: 1, right: 1, top: 0, bottom: 0 })
+            )
+            .style(Style::default().bg(Color::Rgb(0x0D, 0x0C, 0x11)));
+
+        frame.render_widget(paragraph, area);
+    }
+
+    /// Render the most recent files, followed by any errors and skipped files.
+    fn render_files(&self, frame: &mut Frame, area: Rect) {
+        let file_style = Style::default().fg(Color::White);
+        let error_style = Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54));
+        let skipped_style = Style::default().fg(Color::Rgb(0x8A, 0x8A, 0x8A));
+
+        let mut lines: Vec<Line> = Vec::new();
+        if let IndexingPhase::Failed(e) = &self.phase {
+            lines.push(Line::from(vec![Span::styled(format!("Error: {}", e), error_style)]));
+        }
+        for error in &self.errors {
+            lines.push(Line::from(vec![Span::styled(format!("failed  {}", error), error_style)]));
+        }
+        for skipped in &self.skipped {
+            lines.push(Line::from(vec![Span::styled(format!("skipped {}", skipped), skipped_style)]));
+        }
+
+        // Fill the rest of the area with the latest files
+        let available = (area.height.saturating_sub(2) as usize).saturating_sub(lines.len());
+        let start = self.files.len().saturating_sub(available);
+        for file in &self.files[start..] {
+            let detail = if file.embedded > 0 {
+                format!("{} chunks, {} embedded", file.chunks, file.embedded)
+            } else {
+                format!("{} chunks, unchanged", file.chunks)
+            };
+            lines.push(Line::from(vec![
+                Span::styled(format!("{}  ", file.path), file_style),
+                Span::styled(detail, skipped_style),
+            ]));
+        }
+
+        let paragraph = Paragraph::new(Text::from(lines))
+            .block(Block::default()
+                .title(" Files ")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54)))
+                .padding(Padding { left: 1, right: 1, top: 0, bottom: 0 })
+            )
+            .style(Style::default().bg(Color::Rgb(0x0D, 0x0C, 0x11)));
+
+        frame.render_widget(paragraph, area);
+    }
+
+    /// Render padding areas with dark background.
+    fn render_padding(&self, frame: &mut Frame, area: Rect) {
+        let paragraph = Paragraph::new("")
+            .style(Style::default().bg(Color::Rgb(0x0D, 0x0C, 0x11)));
+        frame.render_widget(paragraph, area);
+    }
+
+    /// Render the instructions for the current phase.
+    fn render_instructions(&self, frame: &mut Frame, area: Rect) {
+        let instructions = match self.phase {
+            IndexingPhase::Running => "'c' or 'esc' to cancel",
+            IndexingPhase::Cancelling => "cancelling...",
+            IndexingPhase::Finished => "'enter' to start chat, 'esc' to go back",
+            IndexingPhase::Failed(_) => "'enter' or 'esc' to go back",
+        };
+
+        let paragraph = Paragraph::new(Line::from(vec![
+            Span::styled(instructions, Style::default().fg(Color::Rgb(0xFD, 0x5F, 0x54))),
+        ]))
+            .alignment(ratatui::layout::Alignment::Center)
+            .style(Style::default().bg(Color::Rgb(0x0D, 0x0C, 0x11)));
+
+        frame.render_widget(paragraph, area);
+    }
+}
+
+/// Actions that can be returned from the indexing screen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexingAction {
+    Continue,
+    Cancel,
+    StartChat,
+    Back,
+}
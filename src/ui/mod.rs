@@ -1,2 +1,3 @@
 pub mod chat_interface;
-pub mod home_screen; 
\ No newline at end of file
+pub mod home_screen;
+pub mod indexing_screen;